@@ -1,9 +1,15 @@
 --- @block insertUser
+-- passwords are hashed by the service (Argon2id), this is 'fishcake'
 INSERT INTO users (username, email, password) VALUES (
   'John Smith',
   'johnsmith@gmail.com',
-  crypt('fishcake', gen_salt('bf'))
+  '$argon2id$v=19$m=19456,t=2,p=1$z0JbK3BJPgKt/4AnuWywVA$87OT4zFTOhu+VUvGEFvZLTZvZm3KuQMw6r8ZYskn0MM'
 ) RETURNING *;
 
 -- @block loginUser
-SELECT * FROM users WHERE email = 'johnsmith@gmail.com' AND password = crypt('fishcake', password);
\ No newline at end of file
+-- the password is checked against the hash by the service
+SELECT * FROM users WHERE email = 'johnsmith@gmail.com';
+
+-- @block grantRole
+INSERT INTO user_roles (user_id, role_id)
+SELECT users.id, roles.id FROM users, roles WHERE users.email = 'johnsmith@gmail.com' AND roles.name = 'admin';
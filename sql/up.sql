@@ -1,12 +1,25 @@
 CREATE EXTENSION IF NOT EXISTS "uuid-ossp";
-CREATE EXTENSION IF NOT EXISTS pgcrypto;
 
 CREATE TABLE IF NOT EXISTS users (
   id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
   username VARCHAR(255) NOT NULL,
   email VARCHAR(260) UNIQUE NOT NULL,
+  -- Argon2id PHC string, or a bcrypt hash from before hashing moved into the service
   password TEXT NOT NULL
 );
 
--- always search email first and then password
-CREATE INDEX IF NOT EXISTS user_creds_idx ON users (email, password);
\ No newline at end of file
+-- logins look users up by email only (covered by its unique index)
+DROP INDEX IF EXISTS user_creds_idx;
+
+CREATE TABLE IF NOT EXISTS roles (
+  id SERIAL PRIMARY KEY,
+  name VARCHAR(64) UNIQUE NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS user_roles (
+  user_id UUID NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  role_id INTEGER NOT NULL REFERENCES roles (id) ON DELETE CASCADE,
+  PRIMARY KEY (user_id, role_id)
+);
+
+INSERT INTO roles (name) VALUES ('admin') ON CONFLICT (name) DO NOTHING;
@@ -1 +1,9 @@
-pub mod tokens;
\ No newline at end of file
+pub mod tokens;
+pub mod keys;
+pub mod scopes;
+pub mod lifetimes;
+pub mod cookies;
+pub mod origins;
+pub mod passwords;
+pub mod password_policy;
+pub mod breaches;
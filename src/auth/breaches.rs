@@ -0,0 +1,120 @@
+use std::path::PathBuf;
+
+use sha1::{Digest, Sha1};
+
+use crate::errors::{AppError, TAppError};
+
+/**
+ * length of the SHA-1 prefix naming each range file
+ */
+const PREFIX_LENGTH: usize = 5;
+
+/**
+ * a local copy of the Pwned Passwords dataset split by k-anonymity range, as the range API serves it:
+ * `{dir}/{first 5 hex of the SHA-1}.txt` holding `{remaining 35 hex}:{count}` lines.
+ * Lookups only read the one range file, nothing leaves the machine.
+ */
+pub struct BreachCorpus {
+  dir: PathBuf,
+  min_count: u64,
+  logger: slog::Logger
+}
+
+impl BreachCorpus {
+  pub fn new(logger: slog::Logger, dir: PathBuf, min_count: u64) -> Result<Self, AppError> {
+    if !dir.is_dir() {
+      return Err(AppError::config_error("INVALID_CONFIG", Some(format!("breached_passwords_dir {} is not a directory", dir.display()))));
+    }
+
+    Ok(Self {
+      dir,
+      min_count,
+      logger
+    })
+  }
+
+  /**
+   * times `password` was seen in breaches, 0 when it is not in the corpus
+   */
+  pub async fn count(&self, password: &str) -> Result<u64, AppError> {
+    let hash = format!("{:X}", Sha1::digest(password.as_bytes()));
+    let (prefix, suffix) = hash.split_at(PREFIX_LENGTH);
+    let path = self.dir.join(format!("{}.txt", prefix));
+
+    let range = match tokio::fs::read_to_string(&path).await {
+      Ok(v) => v,
+      // an incomplete corpus should not lock users out of registering
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+        warn!(self.logger, "breached passwords range {} is missing from {}", prefix, self.dir.display());
+        return Ok(0);
+      },
+      Err(err) => return Err(AppError::crit_error(self.logger.clone(), "CANNOT_READ_BREACHES", Some(format!("{}: {}", path.display(), err))))
+    };
+
+    let count = range.lines()
+    .filter_map(|line| line.trim().split_once(':'))
+    .find(|(candidate, _)| candidate.eq_ignore_ascii_case(suffix))
+    .and_then(|(_, count)| count.trim().parse::<u64>().ok())
+    .unwrap_or(0);
+    Ok(count)
+  }
+
+  /**
+   * 400 BREACHED_PASSWORD when `password` was seen in at least `min_count` breaches
+   */
+  pub async fn check(&self, password: &str) -> Result<(), AppError> {
+    let count = self.count(password).await?;
+    if count < self.min_count {
+      return Ok(());
+    }
+
+    Err(AppError::user_error(400, "BREACHED_PASSWORD", Some("this password has appeared in a data breach, please choose another one"))
+    .with_details(serde_json::json!({ "breach_count": count })))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /**
+   * a corpus in a fresh temporary directory holding the given range files
+   */
+  fn corpus(ranges: &[(&str, &str)], min_count: u64) -> BreachCorpus {
+    let dir = std::env::temp_dir().join(format!("userauth-breaches-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir(&dir).unwrap();
+    for (prefix, content) in ranges {
+      std::fs::write(dir.join(format!("{}.txt", prefix)), content).unwrap();
+    }
+    BreachCorpus::new(slog::Logger::root(slog::Discard, o!()), dir, min_count).unwrap()
+  }
+
+  // SHA-1 of "hunter2" is F3BBBD66A63D4BF1747940578EC3D0103530E21D, of "letmein-now" 6C194038455F4B9939F744ACAD5EA3E831C7B88B
+  const HUNTER2_RANGE: (&str, &str) = ("F3BBB", "0018A45C4D1DEF81644B54AB7F969B88D65:1\r\nd66a63d4bf1747940578ec3d0103530e21d:24230\r\n");
+  const LETMEIN_RANGE: (&str, &str) = ("6C194", "038455F4B9939F744ACAD5EA3E831C7B88B:3\n00D4F6E8FA6EECAD2A3AA415EEC418D38EC:2\n");
+
+  #[tokio::test]
+  async fn counts_suffixes_of_either_case() {
+    let corpus = corpus(&[HUNTER2_RANGE, LETMEIN_RANGE], 1);
+    assert_eq!(corpus.count("hunter2").await.unwrap(), 24230);
+    assert_eq!(corpus.count("letmein-now").await.unwrap(), 3);
+    // same range, suffix not listed
+    assert_eq!(corpus.count("Hunter2").await.unwrap(), 0);
+  }
+
+  #[tokio::test]
+  async fn missing_range_counts_as_unseen() {
+    let corpus = corpus(&[HUNTER2_RANGE], 1);
+    assert_eq!(corpus.count("letmein-now").await.unwrap(), 0);
+    assert!(corpus.check("letmein-now").await.is_ok());
+  }
+
+  #[tokio::test]
+  async fn check_applies_min_count() {
+    let corpus = corpus(&[HUNTER2_RANGE, LETMEIN_RANGE], 4);
+    assert!(corpus.check("letmein-now").await.is_ok());
+    let err = corpus.check("hunter2").await.unwrap_err();
+    assert_eq!(err.error_code, "BREACHED_PASSWORD");
+    assert_eq!(err.details, Some(serde_json::json!({ "breach_count": 24230 })));
+  }
+}
@@ -0,0 +1,82 @@
+use std::sync::Arc;
+
+use actix_web::{HttpRequest, cookie::{Cookie, SameSite, time::Duration}};
+
+use crate::{errors::{AppError, TAppError}, config::{AppConfig, FromConfig}, auth::tokens::TokenPair};
+
+pub type CookiePolicyRef = Arc<CookiePolicy>;
+
+/**
+ * attributes of the refresh token cookie, applied everywhere it is set, read or cleared
+ */
+pub struct CookiePolicy {
+  name: String,
+  domain: Option<String>,
+  path: String,
+  same_site: SameSite,
+  secure: bool
+}
+
+impl CookiePolicy {
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  fn build(&self, value: String, max_age: Duration) -> Cookie<'static> {
+    let mut cookie = Cookie::build(self.name.clone(), value)
+    .path(self.path.clone())
+    .same_site(self.same_site)
+    .http_only(true)
+    .secure(self.secure)
+    .max_age(max_age)
+    .finish();
+    if let Some(domain) = &self.domain {
+      cookie.set_domain(domain.clone());
+    }
+    cookie
+  }
+
+  /**
+   * the refresh token cookie, expiring together with the token
+   */
+  pub fn refresh_cookie(&self, tokens: &TokenPair) -> Cookie<'static> {
+    self.build(tokens.refresh_token.clone(), Duration::seconds(tokens.refresh_token_ttl as i64))
+  }
+
+  /**
+   * clears the refresh token cookie, same name, domain and path as the one being replaced
+   */
+  pub fn expired_cookie(&self) -> Cookie<'static> {
+    self.build(String::new(), Duration::ZERO)
+  }
+
+  /**
+   * the refresh token sent in the cookie, if any
+   */
+  pub fn read(&self, req: &HttpRequest) -> Result<Option<String>, AppError> {
+    let cookie = req.cookies()
+    .map_err(|e| AppError::auth_error(400, "CANNOT_PARSE_COOKIES", Some(e.to_string())))?
+    .iter()
+    .find(|cookie| cookie.name() == self.name)
+    .map(|cookie| cookie.value().to_string());
+    Ok(cookie)
+  }
+}
+
+impl FromConfig for CookiePolicy {
+  type Item = Self;
+  fn from_config(logger: slog::Logger, config: &AppConfig) -> Self::Item {
+    let name = match config.cookie_host_prefix {
+      true => format!("__Host-{}", config.cookie_name),
+      false => config.cookie_name.clone()
+    };
+
+    Self {
+      name,
+      domain: config.cookie_domain.clone(),
+      path: config.cookie_path.clone(),
+      same_site: config.cookie_same_site,
+      secure: config.cookie_secure
+    }
+  }
+}
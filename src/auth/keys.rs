@@ -0,0 +1,495 @@
+use std::{path::Path, sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard}};
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use jsonwebtoken::{
+  Algorithm, DecodingKey, EncodingKey, Header, Validation, TokenData,
+  errors::ErrorKind,
+  jwk::{
+    AlgorithmParameters, CommonParameters, EllipticCurve, EllipticCurveKeyParameters, EllipticCurveKeyType,
+    Jwk, JwkSet, OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse, RSAKeyParameters, RSAKeyType
+  }
+};
+use serde::{Serialize, de::DeserializeOwned};
+use simple_asn1::ASN1Block;
+
+use crate::{
+  config::{AppConfig, FromConfig, KeyConfig},
+  errors::{AppError, TAppError},
+  auth::lifetimes::LifetimePolicy
+};
+
+pub type KeyRingRef = Arc<RwLock<KeyRing>>;
+
+pub struct SigningKey {
+  pub kid: String,
+  pub alg: Algorithm,
+  /**
+   * `None` for keys that only verify tokens signed elsewhere / earlier
+   */
+  encoding: Option<EncodingKey>,
+  decoding: DecodingKey,
+  /**
+   * public half published at the jwks endpoint, `None` for HMAC keys
+   */
+  jwk: Option<Jwk>
+}
+
+impl SigningKey {
+  pub fn from_secret(kid: String, alg: Algorithm, secret: &[u8]) -> Self {
+    Self {
+      kid,
+      alg,
+      encoding: Some(EncodingKey::from_secret(secret)),
+      decoding: DecodingKey::from_secret(secret),
+      jwk: None
+    }
+  }
+
+  /**
+   * `private_pem` must be PKCS#8 for ES256/EdDSA, RSA keys may also be PKCS#1.
+   * Without a private key the key can only verify.
+   */
+  pub fn from_pem(kid: String, alg: Algorithm, private_pem: Option<&[u8]>, public_pem: &[u8]) -> Result<Self, AppError> {
+    let invalid_key = |err: jsonwebtoken::errors::Error| AppError::config_error("INVALID_JWT_KEY", Some(format!("{:?} key {}: {}", alg, kid, err)));
+
+    let (encoding, decoding) = match alg {
+      Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512 | Algorithm::PS256 | Algorithm::PS384 | Algorithm::PS512 => (
+        private_pem.map(EncodingKey::from_rsa_pem).transpose().map_err(invalid_key)?,
+        DecodingKey::from_rsa_pem(public_pem).map_err(invalid_key)?
+      ),
+      Algorithm::ES256 | Algorithm::ES384 => (
+        private_pem.map(EncodingKey::from_ec_pem).transpose().map_err(invalid_key)?,
+        DecodingKey::from_ec_pem(public_pem).map_err(invalid_key)?
+      ),
+      Algorithm::EdDSA => (
+        private_pem.map(EncodingKey::from_ed_pem).transpose().map_err(invalid_key)?,
+        DecodingKey::from_ed_pem(public_pem).map_err(invalid_key)?
+      ),
+      Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => return Err(AppError::config_error("INVALID_JWT_KEY", Some(format!("{:?} is symmetric, use a secret instead of key files", alg))))
+    };
+
+    let jwk = public_jwk(&kid, alg, public_pem)?;
+
+    Ok(Self {
+      kid,
+      alg,
+      encoding,
+      decoding,
+      jwk: Some(jwk)
+    })
+  }
+
+  pub fn from_pem_files(kid: String, alg: Algorithm, private_path: Option<&Path>, public_path: &Path) -> Result<Self, AppError> {
+    let read = |path: &Path| std::fs::read(path)
+    .map_err(|err| AppError::config_error("CANNOT_READ_JWT_KEY", Some(format!("{}: {}", path.display(), err))));
+
+    let private_pem = private_path.map(read).transpose()?;
+    Self::from_pem(kid, alg, private_pem.as_deref(), &read(public_path)?)
+  }
+
+  pub fn from_key_config(key: &KeyConfig) -> Result<Self, AppError> {
+    match (&key.secret, &key.public_key_path) {
+      (Some(secret), _) if is_symmetric(key.algorithm) => Ok(Self::from_secret(key.kid.clone(), key.algorithm, secret.as_bytes())),
+      (_, Some(public_path)) if !is_symmetric(key.algorithm) => Self::from_pem_files(key.kid.clone(), key.algorithm, key.private_key_path.as_deref(), public_path),
+      _ => Err(AppError::config_error("MISSING_JWT_KEY", Some(format!("key {} needs a secret ({:?}) or a public_key_path", key.kid, key.algorithm))))
+    }
+  }
+
+  pub fn header(&self) -> Header {
+    let mut header = Header::new(self.alg);
+    header.kid = Some(self.kid.clone());
+    header
+  }
+
+  pub fn encode<T: Serialize>(&self, claims: &T) -> jsonwebtoken::errors::Result<String> {
+    match &self.encoding {
+      Some(encoding) => jsonwebtoken::encode(&self.header(), claims, encoding),
+      None => Err(ErrorKind::InvalidKeyFormat.into())
+    }
+  }
+
+  pub fn decoding_key(&self) -> &DecodingKey {
+    &self.decoding
+  }
+
+  pub fn validation(&self) -> Validation {
+    Validation::new(self.alg)
+  }
+
+  pub fn jwk(&self) -> Option<&Jwk> {
+    self.jwk.as_ref()
+  }
+}
+
+/**
+ * registered claims every token of a key set is issued with and validated against
+ */
+#[derive(Debug, Clone)]
+pub struct ClaimRules {
+  pub issuer: String,
+  pub audience: String,
+  /**
+   * seconds of clock skew tolerated on `exp` and `nbf`
+   */
+  pub leeway: u64
+}
+
+impl ClaimRules {
+  fn apply(&self, validation: &mut Validation) {
+    validation.set_issuer(&[&self.issuer]);
+    validation.set_audience(&[&self.audience]);
+    validation.set_required_spec_claims(&["exp", "nbf", "iss", "aud", "sub"]);
+    validation.validate_nbf = true;
+    validation.leeway = self.leeway;
+  }
+}
+
+impl FromConfig for ClaimRules {
+  type Item = Self;
+  fn from_config(logger: slog::Logger, config: &AppConfig) -> Self::Item {
+    Self {
+      issuer: config.jwt_issuer.clone(),
+      audience: config.jwt_audience.clone(),
+      leeway: config.jwt_leeway
+    }
+  }
+}
+
+/**
+ * one signing key plus every key still accepted for verification, selected by `kid`
+ */
+pub struct KeySet {
+  signing_kid: String,
+  /**
+   * how long a demoted signing key keeps verifying
+   */
+  max_token_lifetime: u64,
+  rules: ClaimRules,
+  entries: Vec<KeyEntry>
+}
+
+struct KeyEntry {
+  key: SigningKey,
+  retire_at: Option<u64>
+}
+
+#[derive(Serialize)]
+pub struct KeyInfo {
+  pub kid: String,
+  pub alg: Algorithm,
+  pub signing: bool,
+  pub can_sign: bool,
+  pub retire_at: Option<u64>
+}
+
+impl KeyEntry {
+  fn is_retired(&self, now: u64) -> bool {
+    self.retire_at.map(|at| at <= now).unwrap_or(false)
+  }
+}
+
+impl KeySet {
+  pub fn new(signing: SigningKey, max_token_lifetime: u64, rules: ClaimRules) -> Self {
+    Self {
+      signing_kid: signing.kid.clone(),
+      max_token_lifetime,
+      rules,
+      entries: vec![KeyEntry { key: signing, retire_at: None }]
+    }
+  }
+
+  /**
+   * adds a key that verifies (and may later be promoted), replacing any key with the same kid
+   */
+  pub fn stage(&mut self, key: SigningKey, retire_at: Option<u64>) -> Result<(), AppError> {
+    if key.kid == self.signing_kid {
+      return Err(AppError::user_error(409, "KID_IS_SIGNING", Some(format!("key {} is the current signing key", key.kid))));
+    }
+    self.entries.retain(|e| e.key.kid != key.kid);
+    self.entries.push(KeyEntry { key, retire_at });
+    Ok(())
+  }
+
+  pub fn signer(&self) -> &SigningKey {
+    // the signing entry is never retired nor removed
+    &self.entries.iter().find(|e| e.key.kid == self.signing_kid).expect("signing key missing from key set").key
+  }
+
+  pub fn rules(&self) -> &ClaimRules {
+    &self.rules
+  }
+
+  pub fn verifier(&self, kid: &str) -> Option<&SigningKey> {
+    let now = jsonwebtoken::get_current_timestamp();
+    self.entries.iter()
+    .find(|e| e.key.kid == kid && !e.is_retired(now))
+    .map(|e| &e.key)
+  }
+
+  /**
+   * verifies with the key named by the token's `kid`, unknown or retired kids fail as an invalid signature.
+   * The registered claims are checked against the set's `ClaimRules`
+   */
+  pub fn decode<T: DeserializeOwned>(&self, token: &str) -> jsonwebtoken::errors::Result<TokenData<T>> {
+    self.decode_with(token, true)
+  }
+
+  /**
+   * same as `decode` but accepts expired tokens, to tell why a token expired
+   */
+  pub fn decode_expired<T: DeserializeOwned>(&self, token: &str) -> jsonwebtoken::errors::Result<TokenData<T>> {
+    self.decode_with(token, false)
+  }
+
+  fn decode_with<T: DeserializeOwned>(&self, token: &str, validate_exp: bool) -> jsonwebtoken::errors::Result<TokenData<T>> {
+    let header = jsonwebtoken::decode_header(token)?;
+    let key = match header.kid.as_deref() {
+      Some(kid) => self.verifier(kid),
+      None => None
+    }
+    .ok_or_else(|| jsonwebtoken::errors::Error::from(ErrorKind::InvalidSignature))?;
+
+    let mut validation = key.validation();
+    self.rules.apply(&mut validation);
+    validation.validate_exp = validate_exp;
+    jsonwebtoken::decode::<T>(token, key.decoding_key(), &validation)
+  }
+
+  /**
+   * makes `kid` the signing key, the previous one keeps verifying until every token it signed has expired
+   */
+  pub fn promote(&mut self, kid: &str) -> Result<(), AppError> {
+    let now = jsonwebtoken::get_current_timestamp();
+    self.sweep();
+
+    let entry = match self.entries.iter_mut().find(|e| e.key.kid == kid) {
+      Some(v) => v,
+      None => return Err(AppError::user_error(404, "KID_NOT_FOUND", Some(format!("no key with kid {}", kid))))
+    };
+    if entry.key.encoding.is_none() {
+      return Err(AppError::user_error(400, "KID_CANNOT_SIGN", Some(format!("key {} has no private key", kid))));
+    }
+    entry.retire_at = None;
+
+    if kid == self.signing_kid {
+      return Ok(());
+    }
+
+    let retire_at = now + self.max_token_lifetime;
+    if let Some(old) = self.entries.iter_mut().find(|e| e.key.kid == self.signing_kid) {
+      old.retire_at = Some(retire_at);
+    }
+    self.signing_kid = kid.into();
+    Ok(())
+  }
+
+  /**
+   * drops keys past their retirement time
+   */
+  pub fn sweep(&mut self) {
+    let now = jsonwebtoken::get_current_timestamp();
+    self.entries.retain(|e| !e.is_retired(now));
+  }
+
+  pub fn jwks(&self) -> Vec<Jwk> {
+    let now = jsonwebtoken::get_current_timestamp();
+    self.entries.iter()
+    .filter(|e| !e.is_retired(now))
+    .filter_map(|e| e.key.jwk().cloned())
+    .collect()
+  }
+
+  pub fn describe(&self) -> Vec<KeyInfo> {
+    let now = jsonwebtoken::get_current_timestamp();
+    self.entries.iter()
+    .filter(|e| !e.is_retired(now))
+    .map(|e| KeyInfo {
+      kid: e.key.kid.clone(),
+      alg: e.key.alg,
+      signing: e.key.kid == self.signing_kid,
+      can_sign: e.key.encoding.is_some(),
+      retire_at: e.retire_at
+    })
+    .collect()
+  }
+}
+
+pub struct KeyRing {
+  pub access: KeySet,
+  pub refresh: KeySet
+}
+
+impl KeyRing {
+  /**
+   * public keys other services can use to verify our access tokens
+   */
+  pub fn jwks(&self) -> JwkSet {
+    JwkSet {
+      keys: self.access.jwks()
+    }
+  }
+
+  pub fn get_readable(keys: &KeyRingRef) -> Result<RwLockReadGuard<'_, Self>, AppError> {
+    keys.read()
+    .map_err(|err| AppError::auth_error(500, "CANNOT_READ_KEYS", Some(err.to_string())))
+  }
+
+  pub fn get_writable(keys: &KeyRingRef) -> Result<RwLockWriteGuard<'_, Self>, AppError> {
+    keys.write()
+    .map_err(|err| AppError::auth_error(500, "CANNOT_WRITE_KEYS", Some(err.to_string())))
+  }
+}
+
+impl FromConfig for KeyRing {
+  type Item = Result<Self, AppError>;
+  fn from_config(logger: slog::Logger, config: &AppConfig) -> Self::Item {
+    let access = match (&config.access_token_secret, &config.jwt_private_key_path, &config.jwt_public_key_path) {
+      (Some(secret), _, _) if is_symmetric(config.jwt_algorithm) => SigningKey::from_secret(config.jwt_kid.clone(), config.jwt_algorithm, secret.as_bytes()),
+      (_, Some(private_path), Some(public_path)) => SigningKey::from_pem_files(config.jwt_kid.clone(), config.jwt_algorithm, Some(private_path), public_path)?,
+      _ => return Err(AppError::config_error("MISSING_JWT_KEY", Some(format!("no key material for {:?}", config.jwt_algorithm))))
+    };
+    info!(logger, "> Signing access tokens with {:?} (kid {})", access.alg, access.kid);
+
+    let rules = ClaimRules::from_config(logger.clone(), config);
+    let lifetimes = LifetimePolicy::from_config(logger.clone(), config);
+    let mut access = KeySet::new(access, lifetimes.max_access(), rules.clone());
+    for key in &config.jwt_keys {
+      access.stage(SigningKey::from_key_config(key)?, key.retire_at)?;
+    }
+
+    let refresh = SigningKey::from_secret(config.refresh_token_kid.clone(), Algorithm::HS256, config.refresh_token_secret.as_bytes());
+    let mut refresh = KeySet::new(refresh, lifetimes.max_refresh(), rules);
+    for key in &config.refresh_keys {
+      refresh.stage(SigningKey::from_key_config(key)?, key.retire_at)?;
+    }
+
+    Ok(Self {
+      access,
+      refresh
+    })
+  }
+}
+
+pub fn is_symmetric(alg: Algorithm) -> bool {
+  matches!(alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512)
+}
+
+/**
+ * builds the JWK of a SubjectPublicKeyInfo (or PKCS#1 RSA) public key pem
+ */
+fn public_jwk(kid: &str, alg: Algorithm, public_pem: &[u8]) -> Result<Jwk, AppError> {
+  let invalid = |msg: &str| AppError::config_error("INVALID_JWT_PUBLIC_KEY", Some(format!("key {}: {}", kid, msg)));
+
+  let pem = pem::parse(public_pem).map_err(|err| invalid(&err.to_string()))?;
+  let key_bytes = if pem.tag == "RSA PUBLIC KEY" {
+    pem.contents
+  } else {
+    match simple_asn1::from_der(&pem.contents).map_err(|err| invalid(&err.to_string()))?.first() {
+      Some(ASN1Block::Sequence(_, spki)) => match spki.get(1) {
+        Some(ASN1Block::BitString(_, _, bits)) => bits.clone(),
+        _ => return Err(invalid("missing subjectPublicKey"))
+      },
+      _ => return Err(invalid("not a SubjectPublicKeyInfo"))
+    }
+  };
+
+  let algorithm = match alg {
+    Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512 | Algorithm::PS256 | Algorithm::PS384 | Algorithm::PS512 => {
+      let (n, e) = match simple_asn1::from_der(&key_bytes).map_err(|err| invalid(&err.to_string()))?.first() {
+        Some(ASN1Block::Sequence(_, parts)) => match (parts.first(), parts.get(1)) {
+          (Some(ASN1Block::Integer(_, n)), Some(ASN1Block::Integer(_, e))) => (n.to_bytes_be().1, e.to_bytes_be().1),
+          _ => return Err(invalid("malformed RSA public key"))
+        },
+        _ => return Err(invalid("malformed RSA public key"))
+      };
+      AlgorithmParameters::RSA(RSAKeyParameters {
+        key_type: RSAKeyType::RSA,
+        n: URL_SAFE_NO_PAD.encode(n),
+        e: URL_SAFE_NO_PAD.encode(e)
+      })
+    },
+    Algorithm::ES256 | Algorithm::ES384 => {
+      // uncompressed point: 0x04 || x || y
+      let (curve, coord_len) = if alg == Algorithm::ES256 { (EllipticCurve::P256, 32) } else { (EllipticCurve::P384, 48) };
+      if key_bytes.len() != 1 + 2 * coord_len || key_bytes[0] != 0x04 {
+        return Err(invalid("expected an uncompressed EC point"));
+      }
+      AlgorithmParameters::EllipticCurve(EllipticCurveKeyParameters {
+        key_type: EllipticCurveKeyType::EC,
+        curve,
+        x: URL_SAFE_NO_PAD.encode(&key_bytes[1..1 + coord_len]),
+        y: URL_SAFE_NO_PAD.encode(&key_bytes[1 + coord_len..])
+      })
+    },
+    Algorithm::EdDSA => {
+      if key_bytes.len() != 32 {
+        return Err(invalid("expected a 32 byte Ed25519 key"));
+      }
+      AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+        key_type: OctetKeyPairType::OctetKeyPair,
+        curve: EllipticCurve::Ed25519,
+        x: URL_SAFE_NO_PAD.encode(&key_bytes)
+      })
+    },
+    _ => return Err(invalid("symmetric keys have no public jwk"))
+  };
+
+  Ok(Jwk {
+    common: CommonParameters {
+      public_key_use: Some(PublicKeyUse::Signature),
+      algorithm: Some(alg),
+      key_id: Some(kid.into()),
+      ..Default::default()
+    },
+    algorithm
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[derive(Serialize, serde::Deserialize)]
+  struct Claims {
+    sub: String,
+    exp: u64
+  }
+
+  /**
+   * signs with the private key and verifies with nothing but the published jwk
+   */
+  fn sign_and_verify_with_jwk(alg: Algorithm, private_pem: &[u8], public_pem: &[u8]) {
+    let key = SigningKey::from_pem("test".into(), alg, Some(private_pem), public_pem).unwrap();
+    let jwk = key.jwk().unwrap();
+    assert_eq!(jwk.common.key_id.as_deref(), Some("test"));
+    assert_eq!(jwk.common.algorithm, Some(alg));
+
+    let claims = Claims {
+      sub: "al".into(),
+      exp: jsonwebtoken::get_current_timestamp() + 60
+    };
+    let token = key.encode(&claims).unwrap();
+    let decoded = jsonwebtoken::decode::<Claims>(&token, &DecodingKey::from_jwk(jwk).unwrap(), &Validation::new(alg)).unwrap();
+    assert_eq!(decoded.claims.sub, "al");
+  }
+
+  #[tokio::test]
+  async fn ed25519_jwk_verifies() {
+    sign_and_verify_with_jwk(Algorithm::EdDSA, include_bytes!("testdata/ed25519.pem"), include_bytes!("testdata/ed25519.pub.pem"));
+  }
+
+  #[tokio::test]
+  async fn p256_jwk_verifies() {
+    sign_and_verify_with_jwk(Algorithm::ES256, include_bytes!("testdata/p256.pem"), include_bytes!("testdata/p256.pub.pem"));
+  }
+
+  #[tokio::test]
+  async fn rsa_jwk_verifies() {
+    sign_and_verify_with_jwk(Algorithm::RS256, include_bytes!("testdata/rsa2048.pem"), include_bytes!("testdata/rsa2048.pub.pem"));
+    // PKCS#1 "RSA PUBLIC KEY" gives the same jwk as the SubjectPublicKeyInfo form
+    sign_and_verify_with_jwk(Algorithm::RS256, include_bytes!("testdata/rsa2048.pem"), include_bytes!("testdata/rsa2048.pkcs1.pub.pem"));
+    let spki = public_jwk("test", Algorithm::RS256, include_bytes!("testdata/rsa2048.pub.pem")).unwrap();
+    let pkcs1 = public_jwk("test", Algorithm::RS256, include_bytes!("testdata/rsa2048.pkcs1.pub.pem")).unwrap();
+    assert_eq!(serde_json::to_value(spki).unwrap(), serde_json::to_value(pkcs1).unwrap());
+  }
+}
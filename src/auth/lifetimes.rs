@@ -0,0 +1,105 @@
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{errors::{AppError, TAppError}, config::{AppConfig, FromConfig}};
+
+pub type LifetimePolicyRef = Arc<LifetimePolicy>;
+
+/**
+ * token lifetimes in seconds, the refresh token lifetime doubles as the idle timeout of a session
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct TokenLifetimes {
+  pub access: u64,
+  pub refresh: u64,
+  pub remember_me_refresh: u64,
+  /**
+   * absolute session lifetime counted from `auth_time`, refreshes cannot extend a session past it
+   */
+  pub session_max_age: u64
+}
+
+impl TokenLifetimes {
+  pub fn refresh(&self, remember_me: bool) -> u64 {
+    if remember_me {
+      self.remember_me_refresh
+    } else {
+      self.refresh
+    }
+  }
+
+  /**
+   * when a session authenticated at `auth_time` must login again
+   */
+  pub fn session_deadline(&self, auth_time: u64) -> u64 {
+    auth_time.saturating_add(self.session_max_age)
+  }
+}
+
+/**
+ * global token lifetimes plus the overrides of each registered client
+ */
+pub struct LifetimePolicy {
+  default: TokenLifetimes,
+  clients: HashMap<String, TokenLifetimes>
+}
+
+impl LifetimePolicy {
+  pub fn new(default: TokenLifetimes, clients: HashMap<String, TokenLifetimes>) -> Self {
+    Self {
+      default,
+      clients
+    }
+  }
+
+  /**
+   * lifetimes of `client_id`, the global ones when no client is given
+   */
+  pub fn for_client(&self, client_id: Option<&str>) -> Result<TokenLifetimes, AppError> {
+    match client_id {
+      None => Ok(self.default),
+      Some(id) => self.clients.get(id).copied()
+      .ok_or_else(|| AppError::user_error(400, "UNKNOWN_CLIENT", Some(format!("no client registered as {}", id))))
+    }
+  }
+
+  fn all(&self) -> impl Iterator<Item = &TokenLifetimes> {
+    std::iter::once(&self.default).chain(self.clients.values())
+  }
+
+  /**
+   * longest access token lifetime of any client, how long access token revocations must be remembered
+   */
+  pub fn max_access(&self) -> u64 {
+    self.all().map(|l| l.access).max().unwrap_or(self.default.access)
+  }
+
+  /**
+   * longest refresh token lifetime of any client, how long refresh token revocations must be remembered
+   */
+  pub fn max_refresh(&self) -> u64 {
+    self.all().map(|l| l.refresh.max(l.remember_me_refresh)).max().unwrap_or(self.default.refresh)
+  }
+}
+
+impl FromConfig for LifetimePolicy {
+  type Item = Self;
+  fn from_config(logger: slog::Logger, config: &AppConfig) -> Self::Item {
+    let default = TokenLifetimes {
+      access: config.access_token_ttl,
+      refresh: config.refresh_token_ttl,
+      remember_me_refresh: config.remember_me_refresh_token_ttl,
+      session_max_age: config.session_max_age
+    };
+
+    let clients = config.clients.iter()
+    .map(|client| (client.id.clone(), TokenLifetimes {
+      access: client.access_token_ttl.unwrap_or(default.access),
+      refresh: client.refresh_token_ttl.unwrap_or(default.refresh),
+      remember_me_refresh: client.remember_me_refresh_token_ttl.unwrap_or(default.remember_me_refresh),
+      session_max_age: client.session_max_age.unwrap_or(default.session_max_age)
+    }))
+    .collect();
+
+    Self::new(default, clients)
+  }
+}
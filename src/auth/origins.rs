@@ -0,0 +1,143 @@
+use std::sync::Arc;
+
+use actix_web::HttpRequest;
+
+use crate::{errors::{AppError, TAppError}, config::{AppConfig, FromConfig}};
+
+pub type OriginPolicyRef = Arc<OriginPolicy>;
+
+/**
+ * origins allowed by a CORS policy, the `/auth` one also decides who may refresh with the cookie (CSRF origin check)
+ */
+pub struct OriginPolicy {
+  allowed: Vec<String>
+}
+
+impl OriginPolicy {
+  pub fn new(allowed: Vec<String>) -> Self {
+    Self {
+      allowed
+    }
+  }
+
+  pub fn is_allowed(&self, origin: &str) -> bool {
+    self.allowed.iter().any(|allowed| origin_matches(allowed, origin))
+  }
+
+  /**
+   * CORS check, browsers also send `Origin` on same-origin POSTs so the server's own host always passes
+   */
+  pub fn is_allowed_cors(&self, origin: &str, host: Option<&str>) -> bool {
+    let same_host = match (origin.split_once("://"), host) {
+      (Some((_, origin_host)), Some(host)) => origin_host.eq_ignore_ascii_case(host),
+      _ => false
+    };
+    same_host || self.is_allowed(origin)
+  }
+
+  /**
+   * 403 unless the request's `Origin` (or the origin of its `Referer`) is the server itself or a trusted origin.
+   * Browsers always send one of them on cross-site POSTs, requests with neither are rejected
+   */
+  pub fn check_request(&self, req: &HttpRequest) -> Result<(), AppError> {
+    let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok());
+    let origin = match (header("Origin"), header("Referer")) {
+      (Some(origin), _) => origin.to_string(),
+      (None, Some(referer)) => origin_of(referer)
+      .ok_or_else(|| AppError::auth_error(403, "UNTRUSTED_ORIGIN", Some("cannot read the origin of the Referer header")))?,
+      (None, None) => return Err(AppError::auth_error(403, "MISSING_ORIGIN", Some("an Origin or Referer header is required")))
+    };
+
+    let own = {
+      let conn = req.connection_info();
+      format!("{}://{}", conn.scheme(), conn.host())
+    };
+    if !origin.eq_ignore_ascii_case(&own) && !self.is_allowed(&origin) {
+      return Err(AppError::auth_error(403, "UNTRUSTED_ORIGIN", Some(format!("requests from {} are not allowed", origin))));
+    }
+    Ok(())
+  }
+}
+
+/**
+ * `pattern` is an origin, `*` for any origin, or has a `*.` host matching subdomains at any depth
+ * (a `*.example.com` host matches `a.b.example.com` but not `example.com`)
+ */
+fn origin_matches(pattern: &str, origin: &str) -> bool {
+  if pattern == "*" {
+    return true;
+  }
+  let (pattern, origin) = (pattern.to_lowercase(), origin.to_lowercase());
+  match pattern.split_once("://*.") {
+    Some((scheme, domain)) => origin.strip_prefix(&format!("{}://", scheme))
+    .and_then(|host| host.strip_suffix(domain))
+    .map(|sub| sub.len() > 1 && sub.ends_with('.') && !sub.contains(['/', ':']))
+    .unwrap_or(false),
+    None => pattern == origin
+  }
+}
+
+/**
+ * `scheme://host[:port]` of a url
+ */
+pub fn origin_of(url: &str) -> Option<String> {
+  let (scheme, rest) = url.split_once("://")?;
+  let host = rest.split(['/', '?', '#']).next().filter(|host| !host.is_empty())?;
+  Some(format!("{}://{}", scheme, host))
+}
+
+impl FromConfig for OriginPolicy {
+  type Item = Self;
+  fn from_config(logger: slog::Logger, config: &AppConfig) -> Self::Item {
+    Self::new(config.cors_auth.allowed_origins.clone())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn exact_origins_match_case_insensitively() {
+    assert!(origin_matches("https://app.example.com", "https://app.example.com"));
+    assert!(origin_matches("https://app.example.com", "HTTPS://App.Example.com"));
+    assert!(!origin_matches("https://app.example.com", "http://app.example.com"));
+    assert!(!origin_matches("https://app.example.com", "https://app.example.com.evil.io"));
+  }
+
+  #[tokio::test]
+  async fn wildcards_match_subdomains_only() {
+    let pattern = "https://*.example.com";
+    assert!(origin_matches(pattern, "https://app.example.com"));
+    assert!(origin_matches(pattern, "https://a.b.example.com"));
+    // the bare apex and look-alike domains ending with the same letters
+    assert!(!origin_matches(pattern, "https://example.com"));
+    assert!(!origin_matches(pattern, "https://evilexample.com"));
+    assert!(!origin_matches(pattern, "https://.example.com"));
+    assert!(!origin_matches(pattern, "http://app.example.com"));
+    assert!(!origin_matches(pattern, "https://app.example.com.evil.io"));
+  }
+
+  #[tokio::test]
+  async fn ports_must_match() {
+    assert!(origin_matches("http://localhost:5173", "http://localhost:5173"));
+    assert!(!origin_matches("http://localhost:5173", "http://localhost:5174"));
+    assert!(!origin_matches("http://localhost:5173", "http://localhost"));
+    assert!(origin_matches("https://*.example.com:8443", "https://app.example.com:8443"));
+    assert!(!origin_matches("https://*.example.com:8443", "https://app.example.com"));
+    assert!(!origin_matches("https://*.example.com", "https://app.example.com:8443"));
+  }
+
+  #[tokio::test]
+  async fn any_origin() {
+    assert!(origin_matches("*", "https://anything.io"));
+  }
+
+  #[tokio::test]
+  async fn origin_of_urls() {
+    assert_eq!(origin_of("https://app.example.com/login?next=/#top").as_deref(), Some("https://app.example.com"));
+    assert_eq!(origin_of("http://localhost:5173").as_deref(), Some("http://localhost:5173"));
+    assert_eq!(origin_of("app.example.com/login"), None);
+    assert_eq!(origin_of("https:///login"), None);
+  }
+}
@@ -0,0 +1,249 @@
+use std::{collections::HashSet, sync::Arc};
+
+use serde::Serialize;
+
+use crate::{errors::{AppError, TAppError}, config::{AppConfig, FromConfig}, auth::breaches::BreachCorpus};
+
+pub type PasswordPolicyRef = Arc<PasswordPolicy>;
+
+/**
+ * rejected whatever the deny list file holds, compared case insensitively
+ */
+const COMMON_PASSWORDS: &[&str] = &[
+  "123456", "12345678", "123456789", "1234567890", "111111", "000000", "123123", "654321",
+  "password", "password1", "password123", "passw0rd", "qwerty", "qwerty123", "qwertyuiop", "1q2w3e4r",
+  "abc123", "iloveyou", "admin", "welcome", "letmein", "monkey", "dragon", "football",
+  "baseball", "sunshine", "princess", "trustno1", "superman", "starwars", "whatever", "changeme"
+];
+
+/**
+ * usernames and email local parts shorter than this are too likely to appear by chance
+ */
+const MIN_USER_INFO_LENGTH: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CharClass {
+  Lowercase,
+  Uppercase,
+  Digit,
+  Symbol
+}
+
+impl CharClass {
+  fn name(&self) -> &'static str {
+    match self {
+      Self::Lowercase => "lowercase",
+      Self::Uppercase => "uppercase",
+      Self::Digit => "digit",
+      Self::Symbol => "symbol"
+    }
+  }
+
+  fn matches(&self, c: char) -> bool {
+    match self {
+      Self::Lowercase => c.is_lowercase(),
+      Self::Uppercase => c.is_uppercase(),
+      Self::Digit => c.is_numeric(),
+      Self::Symbol => !c.is_alphanumeric()
+    }
+  }
+}
+
+impl std::str::FromStr for CharClass {
+  type Err = AppError;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.trim().to_lowercase().as_str() {
+      "lowercase" => Ok(Self::Lowercase),
+      "uppercase" => Ok(Self::Uppercase),
+      "digit" => Ok(Self::Digit),
+      "symbol" => Ok(Self::Symbol),
+      _ => Err(AppError::config_error("INVALID_CONFIG", Some(format!("unsupported password class {}, expected lowercase, uppercase, digit or symbol", s))))
+    }
+  }
+}
+
+/**
+ * one rule a password broke, listed in the `details` of a WEAK_PASSWORD error
+ */
+#[derive(Debug, Serialize)]
+pub struct FailedRule {
+  pub rule: String,
+  pub message: String
+}
+
+impl FailedRule {
+  fn new<T: ToString, R: ToString>(rule: T, message: R) -> Self {
+    Self {
+      rule: rule.to_string(),
+      message: message.to_string()
+    }
+  }
+}
+
+/**
+ * rules new passwords must follow, checked wherever a password is set
+ */
+pub struct PasswordPolicy {
+  min_length: usize,
+  max_length: usize,
+  required_classes: Vec<CharClass>,
+  deny_list: HashSet<String>,
+  reject_user_info: bool,
+  breaches: Option<BreachCorpus>
+}
+
+impl PasswordPolicy {
+  /**
+   * every rule `password` breaks, empty when it is acceptable
+   */
+  pub fn failed_rules(&self, password: &str, username: &str, email: &str) -> Vec<FailedRule> {
+    let mut failed = vec![];
+    let length = password.chars().count();
+    if length < self.min_length {
+      failed.push(FailedRule::new("min_length", format!("must be at least {} characters long", self.min_length)));
+    }
+    if length > self.max_length {
+      failed.push(FailedRule::new("max_length", format!("must be at most {} characters long", self.max_length)));
+    }
+
+    for class in &self.required_classes {
+      if !password.chars().any(|c| class.matches(c)) {
+        failed.push(FailedRule::new(format!("require_{}", class.name()), format!("must contain at least one {} character", class.name())));
+      }
+    }
+
+    let lowered = password.to_lowercase();
+    if self.deny_list.contains(&lowered) {
+      failed.push(FailedRule::new("common_password", "is too common"));
+    }
+
+    if self.reject_user_info {
+      let username = username.trim().to_lowercase();
+      if username.chars().count() >= MIN_USER_INFO_LENGTH && lowered.contains(&username) {
+        failed.push(FailedRule::new("contains_username", "must not contain the username"));
+      }
+      let email = email.trim().to_lowercase();
+      let local = email.split('@').next().unwrap_or_default();
+      if local.chars().count() >= MIN_USER_INFO_LENGTH && lowered.contains(local) {
+        failed.push(FailedRule::new("contains_email", "must not contain the email"));
+      }
+    }
+
+    failed
+  }
+
+  /**
+   * 400 WEAK_PASSWORD listing the failed rules in `details`,
+   * then 400 BREACHED_PASSWORD if the breach corpus is configured and holds the password
+   */
+  pub async fn check(&self, password: &str, username: &str, email: &str) -> Result<(), AppError> {
+    let failed = self.failed_rules(password, username, email);
+    if !failed.is_empty() {
+      return Err(AppError::user_error(400, "WEAK_PASSWORD", Some("the password does not meet the password policy")).with_details(failed));
+    }
+
+    match &self.breaches {
+      Some(breaches) => breaches.check(password).await,
+      None => Ok(())
+    }
+  }
+}
+
+impl FromConfig for PasswordPolicy {
+  type Item = Result<Self, AppError>;
+  fn from_config(logger: slog::Logger, config: &AppConfig) -> Self::Item {
+    let mut deny_list: HashSet<String> = COMMON_PASSWORDS.iter().map(|v| v.to_string()).collect();
+    if let Some(path) = &config.password_deny_list_path {
+      let raw = std::fs::read_to_string(path)
+      .map_err(|err| AppError::config_error("CANNOT_READ_CONFIG", Some(format!("{}: {}", path.display(), err))))?;
+      let before = deny_list.len();
+      deny_list.extend(raw.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_lowercase()));
+      info!(logger, "> Loaded {} denied passwords from {}", deny_list.len() - before, path.display());
+    }
+
+    let breaches = match &config.breached_passwords_dir {
+      Some(dir) => {
+        info!(logger, "> Checking new passwords against the breach corpus in {}", dir.display());
+        Some(BreachCorpus::new(logger.clone(), dir.clone(), config.breached_password_min_count)?)
+      },
+      None => None
+    };
+
+    Ok(Self {
+      min_length: config.password_min_length,
+      max_length: config.password_max_length,
+      required_classes: config.password_required_classes.clone(),
+      deny_list,
+      reject_user_info: config.password_reject_user_info,
+      breaches
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn policy(required_classes: Vec<CharClass>) -> PasswordPolicy {
+    PasswordPolicy {
+      min_length: 8,
+      max_length: 16,
+      required_classes,
+      deny_list: COMMON_PASSWORDS.iter().map(|v| v.to_string()).collect(),
+      reject_user_info: true,
+      breaches: None
+    }
+  }
+
+  fn rules(policy: &PasswordPolicy, password: &str, username: &str, email: &str) -> Vec<String> {
+    policy.failed_rules(password, username, email).into_iter().map(|failed| failed.rule).collect()
+  }
+
+  #[tokio::test]
+  async fn length_counts_characters_not_bytes() {
+    let policy = policy(vec![]);
+    // 7 characters but 14 bytes
+    assert_eq!(rules(&policy, "ééééééé", "al", "a@x.io"), ["min_length"]);
+    // 16 characters but 32 bytes
+    assert!(rules(&policy, "éééééééééééééééé", "al", "a@x.io").is_empty());
+    assert_eq!(rules(&policy, "éééééééééééééééééé", "al", "a@x.io"), ["max_length"]);
+  }
+
+  #[tokio::test]
+  async fn each_class_is_required() {
+    let policy = policy(vec![CharClass::Lowercase, CharClass::Uppercase, CharClass::Digit, CharClass::Symbol]);
+    assert!(rules(&policy, "Correct-Horse-9", "al", "a@x.io").is_empty());
+    assert_eq!(rules(&policy, "CORRECT-HORSE-9", "al", "a@x.io"), ["require_lowercase"]);
+    assert_eq!(rules(&policy, "correct-horse-9", "al", "a@x.io"), ["require_uppercase"]);
+    assert_eq!(rules(&policy, "Correct-Horse-x", "al", "a@x.io"), ["require_digit"]);
+    assert_eq!(rules(&policy, "CorrectHorse9xx", "al", "a@x.io"), ["require_symbol"]);
+    // non ascii letters count towards their case, not as symbols
+    assert!(rules(&policy, "Çorrect-Hörse-9", "al", "a@x.io").is_empty());
+    assert_eq!(rules(&policy, "ÇorrectHörse9xx", "al", "a@x.io"), ["require_symbol"]);
+  }
+
+  #[tokio::test]
+  async fn deny_list_ignores_case() {
+    let policy = policy(vec![]);
+    assert_eq!(rules(&policy, "password", "al", "a@x.io"), ["common_password"]);
+    assert_eq!(rules(&policy, "PassWord123", "al", "a@x.io"), ["common_password"]);
+    assert!(rules(&policy, "password-123", "al", "a@x.io").is_empty());
+  }
+
+  #[tokio::test]
+  async fn rejects_user_info_from_min_length() {
+    let policy = policy(vec![]);
+    assert_eq!(rules(&policy, "xx-Albert-xx", "albert", "someone@x.io"), ["contains_username"]);
+    assert_eq!(rules(&policy, "xx-SOMEONE-xx", "albert", "someone@x.io"), ["contains_email"]);
+    assert_eq!(rules(&policy, "albert@home", " Albert ", "albert@x.io"), ["contains_username", "contains_email"]);
+    // shorter than MIN_USER_INFO_LENGTH, too likely to appear by chance
+    assert!(rules(&policy, "xx-al-xx-bo", "al", "bo@x.io").is_empty());
+    assert_eq!(rules(&policy, "xx-alb-xx-bob", "alb", "bob@x.io"), ["contains_username", "contains_email"]);
+
+    let policy = PasswordPolicy { reject_user_info: false, ..policy };
+    assert!(rules(&policy, "xx-Albert-xx", "albert", "albert@x.io").is_empty());
+  }
+}
@@ -0,0 +1,105 @@
+use std::sync::Arc;
+
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version, password_hash::SaltString};
+use rand_core::OsRng;
+
+use crate::{errors::{AppError, TAppError}, config::{AppConfig, FromConfig}};
+
+pub type PasswordHashingRef = Arc<PasswordHashing>;
+
+/**
+ * outcome of checking a password against a stored hash
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PasswordCheck {
+  Mismatch,
+  Match,
+  /**
+   * the password is right but the hash is bcrypt or uses other Argon2id parameters, it should be replaced
+   */
+  MatchOutdated
+}
+
+/**
+ * Argon2id password hashing, stored as PHC strings (`$argon2id$v=19$m=...`).
+ * Bcrypt hashes made by pgcrypto before hashing moved into the service still verify.
+ * Hashing is slow on purpose, so it runs on the blocking thread pool.
+ */
+pub struct PasswordHashing {
+  params: Params,
+  /**
+   * checked when no user matches, so unknown emails take as long as wrong passwords
+   */
+  dummy_hash: String
+}
+
+impl PasswordHashing {
+  pub fn new(params: Params) -> Result<Self, AppError> {
+    let dummy_hash = hash_with(&params, "dummy password")?;
+    Ok(Self {
+      params,
+      dummy_hash
+    })
+  }
+
+  pub async fn hash(&self, password: &str) -> Result<String, AppError> {
+    let (params, password) = (self.params.clone(), password.to_string());
+    tokio::task::spawn_blocking(move || hash_with(&params, &password)).await
+    .map_err(|err| AppError::auth_error(500, "CANNOT_HASH_PASSWORD", Some(err.to_string())))?
+  }
+
+  /**
+   * `hash` is `None` when the user does not exist, the dummy hash is then checked and the result is always a mismatch
+   */
+  pub async fn verify(&self, password: &str, hash: Option<&str>) -> Result<PasswordCheck, AppError> {
+    let (params, password) = (self.params.clone(), password.to_string());
+    let (hash, exists) = match hash {
+      Some(v) => (v.to_string(), true),
+      None => (self.dummy_hash.clone(), false)
+    };
+    let check = tokio::task::spawn_blocking(move || verify_with(&params, &password, &hash)).await
+    .map_err(|err| AppError::auth_error(500, "CANNOT_VERIFY_PASSWORD", Some(err.to_string())))??;
+
+    Ok(if exists { check } else { PasswordCheck::Mismatch })
+  }
+}
+
+fn argon2(params: &Params) -> Argon2<'static> {
+  Argon2::new(Algorithm::Argon2id, Version::V0x13, params.clone())
+}
+
+fn hash_with(params: &Params, password: &str) -> Result<String, AppError> {
+  let salt = SaltString::generate(&mut OsRng);
+  argon2(params).hash_password(password.as_bytes(), &salt)
+  .map(|hash| hash.to_string())
+  .map_err(|err| AppError::auth_error(500, "CANNOT_HASH_PASSWORD", Some(err.to_string())))
+}
+
+fn verify_with(params: &Params, password: &str, hash: &str) -> Result<PasswordCheck, AppError> {
+  // pgcrypto's gen_salt('bf') hashes
+  if hash.starts_with("$2") {
+    let matches = bcrypt::verify(password, hash)
+    .map_err(|err| AppError::auth_error(500, "INVALID_PASSWORD_HASH", Some(err.to_string())))?;
+    return Ok(if matches { PasswordCheck::MatchOutdated } else { PasswordCheck::Mismatch });
+  }
+
+  let parsed = PasswordHash::new(hash)
+  .map_err(|err| AppError::auth_error(500, "INVALID_PASSWORD_HASH", Some(err.to_string())))?;
+  // the hash carries its own algorithm and parameters, the configured ones only decide whether it is outdated
+  if argon2(params).verify_password(password.as_bytes(), &parsed).is_err() {
+    return Ok(PasswordCheck::Mismatch);
+  }
+
+  let current = parsed.algorithm == Algorithm::Argon2id.ident()
+  && Params::try_from(&parsed).map(|used| used.m_cost() == params.m_cost() && used.t_cost() == params.t_cost() && used.p_cost() == params.p_cost()).unwrap_or(false);
+  Ok(if current { PasswordCheck::Match } else { PasswordCheck::MatchOutdated })
+}
+
+impl FromConfig for PasswordHashing {
+  type Item = Result<Self, AppError>;
+  fn from_config(logger: slog::Logger, config: &AppConfig) -> Self::Item {
+    let params = Params::new(config.argon2_memory_kib, config.argon2_iterations, config.argon2_parallelism, None)
+    .map_err(|err| AppError::config_error("INVALID_CONFIG", Some(format!("invalid argon2 parameters: {}", err))))?;
+    Self::new(params)
+  }
+}
@@ -0,0 +1,55 @@
+use crate::errors::{AppError, TAppError};
+
+use super::tokens::Identity;
+
+/**
+ * every scope an access token can carry, with the role a user needs to be granted it
+ */
+pub const SCOPES: &[(&str, Option<&str>)] = &[
+  ("read", None),
+  ("write", None),
+  ("admin", Some("admin"))
+];
+
+/**
+ * scopes the user may be granted
+ */
+pub fn allowed(identity: &Identity) -> Vec<String> {
+  SCOPES.iter()
+  .filter(|(_, role)| role.map(|role| identity.has_role(role)).unwrap_or(true))
+  .map(|(scope, _)| scope.to_string())
+  .collect()
+}
+
+/**
+ * narrows a space separated scope request to what the user is allowed, no request grants everything allowed.
+ * Unknown or disallowed scopes are dropped, a request left with nothing is a 400 `INVALID_SCOPE`
+ */
+pub fn narrow(identity: &Identity, requested: Option<&str>) -> Result<Vec<String>, AppError> {
+  let allowed = allowed(identity);
+  let requested = match requested {
+    Some(v) => v,
+    None => return Ok(allowed)
+  };
+
+  let granted: Vec<String> = allowed.into_iter()
+  .filter(|scope| requested.split_whitespace().any(|r| r == scope))
+  .collect();
+  if granted.is_empty() {
+    return Err(AppError::user_error(400, "INVALID_SCOPE", Some(format!("none of the requested scopes can be granted, available scopes are: {}", SCOPES.iter().map(|(s, _)| *s).collect::<Vec<_>>().join(" ")))));
+  }
+  Ok(granted)
+}
+
+/**
+ * 403 `INSUFFICIENT_SCOPE` with the RFC 6750 challenge unless `scope` was granted
+ */
+pub fn require(granted: &[String], scope: &str) -> Result<(), AppError> {
+  if !granted.iter().any(|s| s == scope) {
+    return Err(
+      AppError::auth_error(403, "INSUFFICIENT_SCOPE", Some(format!("this route requires the {} scope", scope)))
+      .with_challenge(format!("Bearer error=\"insufficient_scope\", scope=\"{}\"", scope))
+    );
+  }
+  Ok(())
+}
@@ -1,77 +1,477 @@
 use std::str::FromStr;
-use redis::Commands;
 
-use jsonwebtoken::{Header, EncodingKey, DecodingKey, Validation};
 use uuid::Uuid;
 
-use crate::{errors::{AppError, TAppError}, db::redis::{RefreshTokenStore, RefreshTokenRedis}};
+use crate::{errors::{AppError, TAppError}, db::token_store::{RefreshTokenStore, StoredSession}, models::session::SessionInfo, auth::{keys::{ClaimRules, KeyRing, KeyRingRef}, lifetimes::{LifetimePolicy, TokenLifetimes}, scopes}};
 
-const ACCESS_TOKEN_SECRET: &[u8] =dotenv!("ACCESS_TOKEN_SECRET").as_bytes();
-const REFRESH_TOKEN_SECRET: &[u8] =dotenv!("REFRESH_TOKEN_SECRET").as_bytes();
+/**
+ * who an access token is issued to, carried in its claims so requests can be authenticated without a database hit
+ */
+#[derive(Debug, Clone)]
+pub struct Identity {
+  pub user_id: Uuid,
+  pub username: String,
+  pub roles: Vec<String>
+}
+
+impl Identity {
+  pub fn has_role(&self, role: &str) -> bool {
+    self.roles.iter().any(|r| r == role)
+  }
+
+  /**
+   * 403 `MISSING_ROLE` unless the user has `role`
+   */
+  pub fn require_role(&self, role: &str) -> Result<(), AppError> {
+    if !self.has_role(role) {
+      return Err(AppError::auth_error(403, "MISSING_ROLE", Some(format!("this route requires the {} role", role))));
+    }
+    Ok(())
+  }
+}
+
+/**
+ * how a session was started, carried in its refresh tokens so every rotation keeps the same scope and lifetimes
+ */
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SessionOptions {
+  /**
+   * space separated scopes, everything the user is allowed when left out
+   */
+  #[serde(default)]
+  pub scope: Option<String>,
+  /**
+   * registered client whose token lifetimes apply, the global ones when left out
+   */
+  #[serde(default)]
+  pub client_id: Option<String>,
+  /**
+   * selects the longer refresh token lifetime
+   */
+  #[serde(default)]
+  pub remember_me: bool,
+}
+
+/**
+ * how the refresh token is handed to the client, chosen at login and register
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenDelivery {
+  /**
+   * httpOnly `jwt` cookie, for browsers
+   */
+  #[default]
+  Cookie,
+  /**
+   * `refresh_token` in the json body, for clients without a cookie jar, refreshed at `POST /auth/token`
+   */
+  Body
+}
+
+/**
+ * a session as recorded in its refresh tokens
+ */
+#[derive(Debug, Clone)]
+pub struct Session {
+  pub user_id: Uuid,
+  pub sid: String,
+  /**
+   * when the user logged in, the session's max age is counted from it
+   */
+  pub auth_time: u64,
+  pub options: SessionOptions
+}
+
+impl Session {
+  /**
+   * seconds left before the session reaches its max age, 401 `SESSION_MAX_AGE_EXCEEDED` once it has
+   */
+  pub fn remaining(&self, lifetimes: &TokenLifetimes) -> Result<u64, AppError> {
+    let remaining = lifetimes.session_deadline(self.auth_time).saturating_sub(jsonwebtoken::get_current_timestamp());
+    if remaining == 0 {
+      return Err(AppError::auth_error(401, "SESSION_MAX_AGE_EXCEEDED", Some("your session has reached its maximum age, please login again")));
+    }
+    Ok(remaining)
+  }
+}
+
+/**
+ * tokens handed out at login and refresh
+ */
+#[derive(Debug)]
+pub struct TokenPair {
+  pub access_token: String,
+  /**
+   * seconds until the access token expires
+   */
+  pub access_token_ttl: u64,
+  pub refresh_token: String,
+  /**
+   * seconds until the refresh token expires, also the max-age of its cookie
+   */
+  pub refresh_token_ttl: u64
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct JWTClaim {
+  /**
+   * user id
+   */
+  sub: String,
+  iss: String,
+  aud: String,
   exp: u64,
-  user_id: String,
+  nbf: u64,
+  /**
+   * issued at, checked against the user's revocation watermark
+   */
+  iat: u64,
+  /**
+   * token id, used to deny single access tokens before they expire
+   */
+  jti: String,
+  /**
+   * when the user logged in, kept across refreshes
+   */
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  auth_time: Option<u64>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  username: Option<String>,
+  /**
+   * role names, granted through the `user_roles` table and refreshed with every access token
+   */
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  roles: Vec<String>,
+  /**
+   * space separated scopes, granted ones on access tokens and the requested ones on refresh tokens
+   */
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  scope: Option<String>,
+  /**
+   * session id, every login starts a new session with its own rotating refresh token
+   */
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  sid: Option<String>,
+  /**
+   * registered client of the session (refresh tokens only)
+   */
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  client_id: Option<String>,
+  /**
+   * session started with "remember me" (refresh tokens only)
+   */
+  #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+  remember_me: bool,
 }
 
 impl JWTClaim {
-  pub fn access_token(user_id: String) -> Result<String, AppError> {
+  /**
+   * registered claims of a token for `sub` valid from now for `ttl` seconds
+   */
+  fn new(rules: &ClaimRules, sub: String, ttl: u64) -> Self {
+    let now = jsonwebtoken::get_current_timestamp();
+    Self {
+      sub,
+      iss: rules.issuer.clone(),
+      aud: rules.audience.clone(),
+      exp: now + ttl,
+      nbf: now,
+      iat: now,
+      jti: Uuid::new_v4().to_string(),
+      auth_time: None,
+      username: None,
+      roles: vec![],
+      scope: None,
+      sid: None,
+      client_id: None,
+      remember_me: false
+    }
+  }
+
+  /**
+   * `scope` is the requested scope, narrowed to what the user is allowed
+   */
+  pub fn access_token(keys: &KeyRingRef, identity: &Identity, session: &Session, ttl: u64) -> Result<String, AppError> {
+    let granted = scopes::narrow(identity, session.options.scope.as_deref())?;
+    let keys = KeyRing::get_readable(keys)?;
     let claim = JWTClaim {
-      exp: jsonwebtoken::get_current_timestamp() + 300,
-      user_id,
+      auth_time: Some(session.auth_time),
+      username: Some(identity.username.clone()),
+      roles: identity.roles.clone(),
+      scope: Some(granted.join(" ")),
+      sid: Some(session.sid.clone()),
+      ..JWTClaim::new(keys.access.rules(), identity.user_id.to_string(), ttl)
     };
 
-    jsonwebtoken::encode(&Header::default(), &claim, &EncodingKey::from_secret(ACCESS_TOKEN_SECRET))
+    keys.access.signer().encode(&claim)
     .map_err(|e| AppError::auth_error(500, "CANNOT_ENC_JWT_ACC", Some(e.to_string())))
   }
 
-  pub fn refresh_token(logger: slog::Logger, rts: &RefreshTokenStore, user_id: String) -> Result<String, AppError> {
-    let claim = JWTClaim {
-      exp: jsonwebtoken::get_current_timestamp() + 172800,
-      user_id: user_id.clone(),
+  /**
+   * mints the refresh token of `session`, valid for `ttl` seconds, and stores it as that session's current one with `info`.
+   * When rotating, `replaces` is the stored value being rotated out, see `TokenStore::set_session`
+   */
+  pub async fn refresh_token(keys: &KeyRingRef, rts: &RefreshTokenStore, session: &Session, info: SessionInfo, ttl: u64, replaces: Option<&str>) -> Result<String, AppError> {
+    let user_id = session.user_id.to_string();
+    let (tok, exp) = {
+      let keys = KeyRing::get_readable(keys)?;
+      let claim = JWTClaim {
+        auth_time: Some(session.auth_time),
+        scope: session.options.scope.clone(),
+        sid: Some(session.sid.clone()),
+        client_id: session.options.client_id.clone(),
+        remember_me: session.options.remember_me,
+        ..JWTClaim::new(keys.refresh.rules(), user_id.clone(), ttl)
+      };
+
+      let tok = keys.refresh.signer().encode(&claim)
+      .map_err(|e| AppError::auth_error(500, "CANNOT_ENC_JWT_REF", Some(e.to_string())))?;
+      (tok, claim.exp)
     };
 
-    let tok = jsonwebtoken::encode(&Header::default(), &claim, &EncodingKey::from_secret(REFRESH_TOKEN_SECRET))
-    .map_err(|e| AppError::auth_error(500, "CANNOT_ENC_JWT_REF", Some(e.to_string())))?;
+    let stored = StoredSession {
+      token: tok,
+      info
+    };
+    rts.set_session(&user_id, &session.sid, &stored, exp, replaces).await?;
+
+    Ok(stored.token)
+  }
 
-    let rts = RefreshTokenRedis::get_readable(logger, rts)?;
+  /**
+   * issues the next access token and refresh token of `session`, with the lifetimes of its client.
+   * Neither outlives the session's max age
+   */
+  pub async fn issue(keys: &KeyRingRef, rts: &RefreshTokenStore, lifetimes: &LifetimePolicy, identity: &Identity, session: &Session, info: SessionInfo, replaces: Option<&str>) -> Result<TokenPair, AppError> {
+    let lifetimes = lifetimes.for_client(session.options.client_id.as_deref())?;
+    let remaining = session.remaining(&lifetimes)?;
+    let refresh_token_ttl = lifetimes.refresh(session.options.remember_me).min(remaining);
 
-    let mut conn = rts.get_conn()?;
+    // the access token goes first, an ungrantable scope must not leave a session behind
+    let access_token_ttl = lifetimes.access.min(remaining);
+    let access_token = JWTClaim::access_token(keys, identity, session, access_token_ttl)?;
+    let refresh_token = JWTClaim::refresh_token(keys, rts, session, info, refresh_token_ttl, replaces).await?;
 
-    conn.set(user_id, tok.clone())
-    .map_err(|_| AppError::crit_error(rts.logger.clone(), "CANNOT_SET_REFTOK", Some("cannot set ref token in redis")))?;
+    Ok(TokenPair {
+      access_token,
+      access_token_ttl,
+      refresh_token,
+      refresh_token_ttl
+    })
+  }
 
-    Ok(tok)
+  /**
+   * starts a new session
+   */
+  pub async fn new_session(keys: &KeyRingRef, rts: &RefreshTokenStore, lifetimes: &LifetimePolicy, identity: &Identity, options: SessionOptions, info: SessionInfo) -> Result<TokenPair, AppError> {
+    let session = Session {
+      user_id: identity.user_id,
+      sid: Uuid::new_v4().to_string(),
+      auth_time: jsonwebtoken::get_current_timestamp(),
+      options
+    };
+    JWTClaim::issue(keys, rts, lifetimes, identity, &session, info, None).await
   }
 
   /**
-   * returns user id
+   * checks the signature and expiry only, see `verify_access_token` for revocation
    */
-  pub fn verify_access_token(at: String) -> Result<Uuid, AppError> {
-    let t = jsonwebtoken::decode::<JWTClaim>(&at, &DecodingKey::from_secret(ACCESS_TOKEN_SECRET), &Validation::default())
+  pub fn decode_access_token(keys: &KeyRingRef, at: &str) -> Result<JWTClaim, AppError> {
+    let t = KeyRing::get_readable(keys)?.access.decode::<JWTClaim>(at)
     .map_err(|err| match err.into_kind() {
       jsonwebtoken::errors::ErrorKind::ExpiredSignature => AppError::auth_error(401, "EXPIRED_ACCESS_TOKEN", Some("your access token has expired, please refresh at /auth/refreshAccessToken")),
       _ => AppError::auth_error(401, "INVALID_ACCESS_TOKEN", Some("cannot decode access token"))
     })?;
+    Ok(t.claims)
+  }
 
-    let user_id = Uuid::from_str(&t.claims.user_id)
-    .map_err(|err| AppError::auth_error(401, "INVALID_UID_ACCESS_TOKEN", Some("invalid user_id in access token")))?;
-    Ok(user_id)
+  /**
+   * returns the identity, granted scopes and session id in the claims,
+   * rejecting denied tokens, tokens of revoked sessions and tokens issued before the user's revocation watermark
+   */
+  pub async fn verify_access_token(keys: &KeyRingRef, rts: &RefreshTokenStore, at: String) -> Result<(Identity, Vec<String>, Option<String>), AppError> {
+    let claims = JWTClaim::decode_access_token(keys, &at)?;
+
+    let (denied, revoked_before) = rts.access_token_status(&claims.sub, claims.sid.as_deref(), &claims.jti).await?;
+    // the watermark also catches tokens whose session was already gone from the index when logoutAll ran
+    let before_watermark = revoked_before.map(|watermark| claims.iat < watermark).unwrap_or(false);
+    if denied || before_watermark {
+      return Err(AppError::auth_error(401, "REVOKED_ACCESS_TOKEN", Some("your access token has been revoked")));
+    }
+
+    let user_id = Uuid::from_str(&claims.sub)
+    .map_err(|err| AppError::auth_error(401, "INVALID_UID_ACCESS_TOKEN", Some("invalid sub in access token")))?;
+    // tokens minted before identities were embedded, a refresh issues a complete one
+    let username = match claims.username {
+      Some(v) => v,
+      None => return Err(AppError::auth_error(401, "INVALID_ACCESS_TOKEN", Some("access token carries no identity, please refresh at /auth/refreshAccessToken")))
+    };
+
+    let scope = claims.scope.as_deref().unwrap_or_default()
+    .split_whitespace()
+    .map(String::from)
+    .collect();
+
+    Ok((Identity {
+      user_id,
+      username,
+      roles: claims.roles
+    }, scope, claims.sid))
   }
 
   /**
-   * returns user id
+   * denies this access token until it expires
    */
-  pub fn verify_refresh_token(rt: String) -> Result<Uuid, AppError> {
-    let t = jsonwebtoken::decode::<JWTClaim>(&rt, &DecodingKey::from_secret(REFRESH_TOKEN_SECRET), &Validation::default())
-    .map_err(|err| match err.into_kind() {
-      jsonwebtoken::errors::ErrorKind::ExpiredSignature => AppError::auth_error(401, "EXPIRED_REFRESH_TOKEN", Some("your refresh token has expired, please login again")),
-      _ => AppError::auth_error(401, "INVALID_ACCESS_TOKEN", Some("cannot decode refresh token"))
-    })?;
+  pub async fn revoke(&self, rts: &RefreshTokenStore) -> Result<(), AppError> {
+    let ttl = self.exp.saturating_sub(jsonwebtoken::get_current_timestamp());
+    if ttl == 0 {
+      return Ok(());
+    }
+    rts.deny_access_token(&self.jti, ttl).await
+  }
 
-    let user_id = Uuid::from_str(&t.claims.user_id)
-    .map_err(|err| AppError::auth_error(401, "INVALID_UID_REFRESH_TOKEN", Some("invalid user_id in refresh token")))?;
-    Ok(user_id)
+  /**
+   * returns the session the refresh token belongs to.
+   * An expired token fails with 401 `SESSION_MAX_AGE_EXCEEDED` when its session is past its max age,
+   * `SESSION_IDLE_TIMEOUT` when it simply was not refreshed in time
+   */
+  pub fn verify_refresh_token(keys: &KeyRingRef, lifetimes: &LifetimePolicy, rt: String) -> Result<Session, AppError> {
+    let keys = KeyRing::get_readable(keys)?;
+    let invalid = || AppError::auth_error(401, "INVALID_REFRESH_TOKEN", Some("cannot decode refresh token"));
+    let (claims, expired) = match keys.refresh.decode::<JWTClaim>(&rt) {
+      Ok(t) => (t.claims, false),
+      Err(err) => match err.into_kind() {
+        jsonwebtoken::errors::ErrorKind::ExpiredSignature => (keys.refresh.decode_expired::<JWTClaim>(&rt).map_err(|_| invalid())?.claims, true),
+        _ => return Err(invalid())
+      }
+    };
+
+    let session = claims.into_session()?;
+    session.remaining(&lifetimes.for_client(session.options.client_id.as_deref())?)?;
+    if expired {
+      return Err(AppError::auth_error(401, "SESSION_IDLE_TIMEOUT", Some("your session was idle for too long, please login again")));
+    }
+    Ok(session)
   }
-}
\ No newline at end of file
+
+  fn into_session(self) -> Result<Session, AppError> {
+    let user_id = Uuid::from_str(&self.sub)
+    .map_err(|err| AppError::auth_error(401, "INVALID_UID_REFRESH_TOKEN", Some("invalid sub in refresh token")))?;
+    let sid = match self.sid {
+      Some(v) => v,
+      None => return Err(AppError::auth_error(401, "NO_SID_REFRESH_TOKEN", Some("refresh token has no session, please login again")))
+    };
+    Ok(Session {
+      user_id,
+      sid,
+      // refresh tokens minted before auth_time was recorded, their session counts from the last refresh
+      auth_time: self.auth_time.unwrap_or(self.iat),
+      options: SessionOptions {
+        scope: self.scope,
+        client_id: self.client_id,
+        remember_me: self.remember_me
+      }
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{collections::HashMap, sync::{Arc, RwLock}};
+
+  use jsonwebtoken::Algorithm;
+
+  use super::*;
+  use crate::{auth::keys::{KeySet, SigningKey}, db::{memory::MemoryTokenStore, token_store}, models::session::ClientInfo};
+
+  fn logger() -> slog::Logger {
+    slog::Logger::root(slog::Discard, o!())
+  }
+
+  fn keys() -> KeyRingRef {
+    let rules = ClaimRules {
+      issuer: "userauth".into(),
+      audience: "userauth".into(),
+      leeway: 0
+    };
+    let key_set = |kid: &str| KeySet::new(SigningKey::from_secret(kid.into(), Algorithm::HS256, kid.as_bytes()), 3600, rules.clone());
+    Arc::new(RwLock::new(KeyRing {
+      access: key_set("access"),
+      refresh: key_set("refresh")
+    }))
+  }
+
+  fn lifetimes() -> LifetimePolicy {
+    LifetimePolicy::new(TokenLifetimes {
+      access: 60,
+      refresh: 600,
+      remember_me_refresh: 3600,
+      session_max_age: 7200
+    }, HashMap::new())
+  }
+
+  fn identity() -> Identity {
+    Identity {
+      user_id: Uuid::new_v4(),
+      username: "al".into(),
+      roles: vec![]
+    }
+  }
+
+  async fn login(keys: &KeyRingRef, rts: &RefreshTokenStore, lifetimes: &LifetimePolicy, identity: &Identity) -> TokenPair {
+    let info = SessionInfo::new(ClientInfo { ip: None, user_agent: None }, None);
+    JWTClaim::new_session(keys, rts, lifetimes, identity, SessionOptions::default(), info).await.unwrap()
+  }
+
+  async fn error_code(keys: &KeyRingRef, rts: &RefreshTokenStore, at: &str) -> Option<String> {
+    JWTClaim::verify_access_token(keys, rts, at.to_string()).await.err().map(|err| err.error_code)
+  }
+
+  #[tokio::test]
+  async fn logout_all_revokes_tokens_of_ended_and_unlisted_sessions() {
+    let (keys, lifetimes, identity) = (keys(), lifetimes(), identity());
+    let rts: RefreshTokenStore = Arc::new(MemoryTokenStore::new(logger()));
+    let a = login(&keys, &rts, &lifetimes, &identity).await;
+    let b = login(&keys, &rts, &lifetimes, &identity).await;
+
+    // an access token of a session that is in no index, issued before logoutAll
+    let unlisted = {
+      let ring = KeyRing::get_readable(&keys).unwrap();
+      let mut claim = JWTClaim {
+        username: Some(identity.username.clone()),
+        sid: Some(Uuid::new_v4().to_string()),
+        ..JWTClaim::new(ring.access.rules(), identity.user_id.to_string(), 60)
+      };
+      claim.iat -= 10;
+      ring.access.signer().encode(&claim).unwrap()
+    };
+    assert_eq!(error_code(&keys, &rts, &unlisted).await, None);
+
+    token_store::end_session(logger(), &keys, &rts, &lifetimes, a.refresh_token.clone()).await.unwrap();
+    assert_eq!(error_code(&keys, &rts, &a.access_token).await.as_deref(), Some("REVOKED_ACCESS_TOKEN"));
+    assert_eq!(error_code(&keys, &rts, &b.access_token).await, None);
+
+    let revoked = token_store::end_all_sessions(&rts, &lifetimes, &identity.user_id.to_string()).await.unwrap();
+    assert_eq!(revoked, 1);
+    for at in [&a.access_token, &b.access_token, &unlisted] {
+      assert_eq!(error_code(&keys, &rts, at).await.as_deref(), Some("REVOKED_ACCESS_TOKEN"));
+    }
+
+    let c = login(&keys, &rts, &lifetimes, &identity).await;
+    assert_eq!(error_code(&keys, &rts, &c.access_token).await, None);
+  }
+
+  #[tokio::test]
+  async fn rejects_bad_refresh_tokens_as_refresh_tokens() {
+    let (keys, lifetimes, identity) = (keys(), lifetimes(), identity());
+    let rts: RefreshTokenStore = Arc::new(MemoryTokenStore::new(logger()));
+    let pair = login(&keys, &rts, &lifetimes, &identity).await;
+
+    assert!(JWTClaim::verify_refresh_token(&keys, &lifetimes, pair.refresh_token).is_ok());
+    for rt in ["garbage".to_string(), pair.access_token] {
+      let err = JWTClaim::verify_refresh_token(&keys, &lifetimes, rt).unwrap_err();
+      assert_eq!(err.error_code, "INVALID_REFRESH_TOKEN");
+    }
+  }
+}
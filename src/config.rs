@@ -1,5 +1,696 @@
-pub trait FromEnv {
+use std::{path::PathBuf, sync::Arc};
+
+use actix_web::cookie::SameSite;
+use clap::Parser;
+use jsonwebtoken::Algorithm;
+
+use crate::{errors::{AppError, TAppError}, auth::{keys::is_symmetric, origins::origin_of, password_policy::CharClass}};
+
+pub type ConfigRef = Arc<AppConfig>;
+
+pub trait FromConfig {
   type Item;
-  
-  fn from_env(logger: slog::Logger) -> Self::Item;
+
+  fn from_config(logger: slog::Logger, config: &AppConfig) -> Self::Item;
+}
+
+/**
+ * Settings resolved at startup, see `AppConfig::load` for precedence
+ */
+#[derive(Debug, Clone)]
+pub struct AppConfig {
+  pub host_addr: String,
+  pub database_url: String,
+  pub token_store: TokenStoreBackend,
+  /**
+   * only used by the redis token store
+   */
+  pub redis_url: Option<String>,
+  /**
+   * only used when `jwt_algorithm` is HMAC based
+   */
+  pub access_token_secret: Option<String>,
+  pub refresh_token_secret: String,
+  pub jwt_algorithm: Algorithm,
+  pub jwt_kid: String,
+  pub jwt_private_key_path: Option<PathBuf>,
+  pub jwt_public_key_path: Option<PathBuf>,
+  /**
+   * extra access token keys (TOML only), either staged for promotion or kept to verify older tokens
+   */
+  pub jwt_keys: Vec<KeyConfig>,
+  pub refresh_token_kid: String,
+  /**
+   * extra refresh token keys (TOML only)
+   */
+  pub refresh_keys: Vec<KeyConfig>,
+  /**
+   * `iss` of every issued token, tokens from other issuers are rejected
+   */
+  pub jwt_issuer: String,
+  /**
+   * `aud` of every issued token, tokens for other audiences are rejected
+   */
+  pub jwt_audience: String,
+  /**
+   * clock skew in seconds tolerated when checking `exp` and `nbf`
+   */
+  pub jwt_leeway: u64,
+  /**
+   * token lifetimes in seconds, used for clients without their own
+   */
+  pub access_token_ttl: u64,
+  pub refresh_token_ttl: u64,
+  /**
+   * refresh token lifetime of sessions started with "remember me"
+   */
+  pub remember_me_refresh_token_ttl: u64,
+  /**
+   * seconds after login past which a session can no longer be refreshed, however active
+   */
+  pub session_max_age: u64,
+  /**
+   * registered clients (TOML only) with their own token lifetimes
+   */
+  pub clients: Vec<ClientConfig>,
+  /**
+   * refresh token cookie attributes, the name gets the `__Host-` prefix when `cookie_host_prefix` is set
+   */
+  pub cookie_name: String,
+  pub cookie_domain: Option<String>,
+  pub cookie_path: String,
+  pub cookie_same_site: SameSite,
+  pub cookie_secure: bool,
+  pub cookie_host_prefix: bool,
+  /**
+   * CORS of the `/auth` scope, its origins are also the ones trusted to refresh with the cookie
+   */
+  pub cors_auth: CorsConfig,
+  /**
+   * CORS of public routes (`/`, the JWKS)
+   */
+  pub cors_public: CorsConfig,
+  /**
+   * Argon2id cost of new password hashes, older hashes are upgraded at login
+   */
+  pub argon2_memory_kib: u32,
+  pub argon2_iterations: u32,
+  pub argon2_parallelism: u32,
+  /**
+   * length bounds of new passwords, in characters
+   */
+  pub password_min_length: usize,
+  pub password_max_length: usize,
+  /**
+   * character classes every new password must contain
+   */
+  pub password_required_classes: Vec<CharClass>,
+  /**
+   * file of extra common passwords, one per line, added to the built in list
+   */
+  pub password_deny_list_path: Option<PathBuf>,
+  /**
+   * rejects new passwords containing the username or the email
+   */
+  pub password_reject_user_info: bool,
+  /**
+   * local Pwned Passwords range files, the breach check is off when unset
+   */
+  pub breached_passwords_dir: Option<PathBuf>,
+  /**
+   * breaches a password must appear in to be rejected
+   */
+  pub breached_password_min_count: u64,
+  /**
+   * enables the `/admin` routes when set
+   */
+  pub admin_api_key: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenStoreBackend {
+  Redis,
+  Memory
+}
+
+impl std::str::FromStr for TokenStoreBackend {
+  type Err = AppError;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.to_lowercase().as_str() {
+      "redis" => Ok(Self::Redis),
+      "memory" => Ok(Self::Memory),
+      _ => Err(AppError::config_error("INVALID_CONFIG", Some(format!("unsupported TOKEN_STORE {}, expected redis or memory", s))))
+    }
+  }
+}
+
+/**
+ * CORS settings of one group of routes
+ */
+#[derive(Debug, Clone)]
+pub struct CorsConfig {
+  /**
+   * `scheme://host[:port]`, a host starting with `*.` for any subdomain, or `*` for any origin (without credentials)
+   */
+  pub allowed_origins: Vec<String>,
+  pub allowed_methods: Vec<String>,
+  pub allowed_headers: Vec<String>,
+  /**
+   * seconds browsers may cache a preflight response
+   */
+  pub max_age: Option<usize>,
+  pub supports_credentials: bool,
+}
+
+/**
+ * a `[cors.auth]` / `[cors.public]` table, unset keys keep the scope's defaults
+ */
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileCorsConfig {
+  allowed_origins: Option<Vec<String>>,
+  allowed_methods: Option<Vec<String>>,
+  allowed_headers: Option<Vec<String>>,
+  max_age: Option<usize>,
+  supports_credentials: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileCors {
+  #[serde(default)]
+  auth: FileCorsConfig,
+  #[serde(default)]
+  public: FileCorsConfig,
+}
+
+impl CorsConfig {
+  fn resolve(file: FileCorsConfig, defaults: Self) -> Result<Self, AppError> {
+    let config = Self {
+      allowed_origins: match file.allowed_origins {
+        Some(origins) => origins.iter().map(|v| parse_origin(v)).collect::<Result<_, _>>()?,
+        None => defaults.allowed_origins
+      },
+      allowed_methods: file.allowed_methods.map(|v| v.iter().map(|m| m.trim().to_uppercase()).collect()).unwrap_or(defaults.allowed_methods),
+      allowed_headers: file.allowed_headers.map(|v| v.iter().map(|h| h.trim().to_lowercase()).collect()).unwrap_or(defaults.allowed_headers),
+      max_age: file.max_age.or(defaults.max_age),
+      supports_credentials: file.supports_credentials.unwrap_or(defaults.supports_credentials),
+    };
+
+    if let Some(method) = config.allowed_methods.iter().find(|m| actix_web::http::Method::from_bytes(m.as_bytes()).is_err()) {
+      return Err(AppError::config_error("INVALID_CONFIG", Some(format!("invalid CORS method {}", method))));
+    }
+    if let Some(header) = config.allowed_headers.iter().find(|h| actix_web::http::header::HeaderName::from_bytes(h.as_bytes()).is_err()) {
+      return Err(AppError::config_error("INVALID_CONFIG", Some(format!("invalid CORS header {}", header))));
+    }
+    // any origin could then act as the logged in user
+    if config.supports_credentials && config.allowed_origins.iter().any(|o| o == "*") {
+      return Err(AppError::config_error("INVALID_CONFIG", Some("CORS origin * cannot be combined with supports_credentials")));
+    }
+    Ok(config)
+  }
+}
+
+/**
+ * `scheme://host[:port]` exactly as browsers send it in the `Origin` header,
+ * the host may start with `*.` to match any subdomain, `*` alone matches every origin
+ */
+fn parse_origin(v: &str) -> Result<String, AppError> {
+  let v = v.trim().trim_end_matches('/');
+  if v == "*" {
+    return Ok(v.into());
+  }
+  let wildcard_ok = v.split_once("://")
+  .map(|(_, host)| !host.contains('*') || (host.starts_with("*.") && !host[2..].contains('*')))
+  .unwrap_or(false);
+  match origin_of(v) {
+    Some(origin) if origin == v && wildcard_ok && (v.starts_with("https://") || v.starts_with("http://")) => Ok(origin),
+    _ => Err(AppError::config_error("INVALID_CONFIG", Some(format!("invalid CORS origin {}, expected scheme://host[:port], scheme://*.host[:port] or *", v))))
+  }
+}
+
+fn parse_same_site(v: &str) -> Result<SameSite, AppError> {
+  match v.to_lowercase().as_str() {
+    "strict" => Ok(SameSite::Strict),
+    "lax" => Ok(SameSite::Lax),
+    "none" => Ok(SameSite::None),
+    _ => Err(AppError::config_error("INVALID_CONFIG", Some(format!("unsupported COOKIE_SAME_SITE {}, expected strict, lax or none", v))))
+  }
+}
+
+/**
+ * a `[[jwt_keys]]` / `[[refresh_keys]]` entry, keys without a private key (or secret) can only verify
+ */
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyConfig {
+  pub kid: String,
+  #[serde(default = "default_key_algorithm")]
+  pub algorithm: Algorithm,
+  pub secret: Option<String>,
+  pub private_key_path: Option<PathBuf>,
+  pub public_key_path: Option<PathBuf>,
+  /**
+   * unix timestamp after which the key no longer verifies
+   */
+  pub retire_at: Option<u64>,
+}
+
+fn default_key_algorithm() -> Algorithm {
+  Algorithm::HS256
+}
+
+/**
+ * a registered client (e.g. web, mobile, cli) selected by the `client_id` sent at login,
+ * unset lifetimes fall back to the global ones
+ */
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientConfig {
+  pub id: String,
+  pub access_token_ttl: Option<u64>,
+  pub refresh_token_ttl: Option<u64>,
+  pub remember_me_refresh_token_ttl: Option<u64>,
+  pub session_max_age: Option<u64>,
+}
+
+// command line flags, each one falls back to its environment variable
+#[derive(Debug, Parser)]
+#[command(version, about = "JWT user auth api")]
+struct CliArgs {
+  /// path to an optional TOML config file
+  #[arg(long, env = "APP_CONFIG")]
+  config: Option<PathBuf>,
+
+  #[arg(long, env = "HOST_ADDR")]
+  host_addr: Option<String>,
+
+  #[arg(long, env = "DATABASE_URL")]
+  database_url: Option<String>,
+
+  /// where refresh tokens and revocations are kept: redis or memory (default redis)
+  #[arg(long, env = "TOKEN_STORE")]
+  token_store: Option<String>,
+
+  #[arg(long, env = "REDIS_URL")]
+  redis_url: Option<String>,
+
+  #[arg(long, env = "ACCESS_TOKEN_SECRET", hide_env_values = true)]
+  access_token_secret: Option<String>,
+
+  #[arg(long, env = "REFRESH_TOKEN_SECRET", hide_env_values = true)]
+  refresh_token_secret: Option<String>,
+
+  /// access token signing algorithm: HS256, RS256, ES256 or EdDSA (default HS256)
+  #[arg(long, env = "JWT_ALGORITHM")]
+  jwt_algorithm: Option<String>,
+
+  /// key id put in the access token header and the jwks (default "primary")
+  #[arg(long, env = "JWT_KID")]
+  jwt_kid: Option<String>,
+
+  /// PEM private key, required for asymmetric algorithms
+  #[arg(long, env = "JWT_PRIVATE_KEY_PATH")]
+  jwt_private_key_path: Option<PathBuf>,
+
+  /// PEM public key, required for asymmetric algorithms
+  #[arg(long, env = "JWT_PUBLIC_KEY_PATH")]
+  jwt_public_key_path: Option<PathBuf>,
+
+  /// key id put in the refresh token header (default "refresh")
+  #[arg(long, env = "REFRESH_TOKEN_KID")]
+  refresh_token_kid: Option<String>,
+
+  /// iss claim of issued tokens and the only issuer accepted (default "userauth")
+  #[arg(long, env = "JWT_ISSUER")]
+  jwt_issuer: Option<String>,
+
+  /// aud claim of issued tokens and the only audience accepted (default "userauth")
+  #[arg(long, env = "JWT_AUDIENCE")]
+  jwt_audience: Option<String>,
+
+  /// seconds of clock skew tolerated on exp and nbf (default 30)
+  #[arg(long, env = "JWT_LEEWAY")]
+  jwt_leeway: Option<u64>,
+
+  /// access token lifetime in seconds (default 300)
+  #[arg(long, env = "ACCESS_TOKEN_TTL")]
+  access_token_ttl: Option<u64>,
+
+  /// refresh token lifetime in seconds (default 172800, 2 days)
+  #[arg(long, env = "REFRESH_TOKEN_TTL")]
+  refresh_token_ttl: Option<u64>,
+
+  /// refresh token lifetime in seconds when logging in with remember_me (default 2592000, 30 days)
+  #[arg(long, env = "REMEMBER_ME_REFRESH_TOKEN_TTL")]
+  remember_me_refresh_token_ttl: Option<u64>,
+
+  /// seconds after login past which a session must login again, however active (default 7776000, 90 days)
+  #[arg(long, env = "SESSION_MAX_AGE")]
+  session_max_age: Option<u64>,
+
+  /// name of the refresh token cookie (default jwt)
+  #[arg(long, env = "COOKIE_NAME")]
+  cookie_name: Option<String>,
+
+  /// domain of the refresh token cookie, host only when unset
+  #[arg(long, env = "COOKIE_DOMAIN")]
+  cookie_domain: Option<String>,
+
+  /// path of the refresh token cookie (default /auth, / with the __Host- prefix)
+  #[arg(long, env = "COOKIE_PATH")]
+  cookie_path: Option<String>,
+
+  /// SameSite of the refresh token cookie: strict, lax or none (default none)
+  #[arg(long, env = "COOKIE_SAME_SITE")]
+  cookie_same_site: Option<String>,
+
+  /// Secure flag of the refresh token cookie (default true)
+  #[arg(long, env = "COOKIE_SECURE")]
+  cookie_secure: Option<bool>,
+
+  /// prefixes the refresh token cookie name with __Host- (default false)
+  #[arg(long, env = "COOKIE_HOST_PREFIX")]
+  cookie_host_prefix: Option<bool>,
+
+  /// comma separated origins allowed to call /auth with credentials, e.g. https://app.example.com,https://*.example.com
+  #[arg(long, env = "ALLOWED_ORIGINS", value_delimiter = ',')]
+  allowed_origins: Option<Vec<String>>,
+
+  /// memory cost of password hashes in KiB (default 19456, 19 MiB)
+  #[arg(long, env = "ARGON2_MEMORY_KIB")]
+  argon2_memory_kib: Option<u32>,
+
+  /// time cost of password hashes in iterations (default 2)
+  #[arg(long, env = "ARGON2_ITERATIONS")]
+  argon2_iterations: Option<u32>,
+
+  /// lanes used to compute a password hash (default 1)
+  #[arg(long, env = "ARGON2_PARALLELISM")]
+  argon2_parallelism: Option<u32>,
+
+  /// minimum length of new passwords in characters (default 8)
+  #[arg(long, env = "PASSWORD_MIN_LENGTH")]
+  password_min_length: Option<usize>,
+
+  /// maximum length of new passwords in characters (default 128)
+  #[arg(long, env = "PASSWORD_MAX_LENGTH")]
+  password_max_length: Option<usize>,
+
+  /// comma separated character classes new passwords must contain: lowercase, uppercase, digit, symbol (default none)
+  #[arg(long, env = "PASSWORD_REQUIRED_CLASSES", value_delimiter = ',')]
+  password_required_classes: Option<Vec<String>>,
+
+  /// file of common passwords to reject, one per line, on top of the built in list
+  #[arg(long, env = "PASSWORD_DENY_LIST_PATH")]
+  password_deny_list_path: Option<PathBuf>,
+
+  /// rejects new passwords containing the username or email (default true)
+  #[arg(long, env = "PASSWORD_REJECT_USER_INFO")]
+  password_reject_user_info: Option<bool>,
+
+  /// directory of Pwned Passwords range files ({prefix}.txt) new passwords are checked against, no check when unset
+  #[arg(long, env = "BREACHED_PASSWORDS_DIR")]
+  breached_passwords_dir: Option<PathBuf>,
+
+  /// breaches a password must appear in to be rejected (default 1)
+  #[arg(long, env = "BREACHED_PASSWORD_MIN_COUNT")]
+  breached_password_min_count: Option<u64>,
+
+  /// key expected in the X-Admin-Key header of /admin routes, which are disabled when unset
+  #[arg(long, env = "ADMIN_API_KEY", hide_env_values = true)]
+  admin_api_key: Option<String>,
+}
+
+/**
+ * Shape of the TOML config file, every key is optional
+ */
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+  host_addr: Option<String>,
+  database_url: Option<String>,
+  token_store: Option<String>,
+  redis_url: Option<String>,
+  access_token_secret: Option<String>,
+  refresh_token_secret: Option<String>,
+  jwt_algorithm: Option<String>,
+  jwt_kid: Option<String>,
+  jwt_private_key_path: Option<PathBuf>,
+  jwt_public_key_path: Option<PathBuf>,
+  #[serde(default)]
+  jwt_keys: Vec<KeyConfig>,
+  refresh_token_kid: Option<String>,
+  #[serde(default)]
+  refresh_keys: Vec<KeyConfig>,
+  jwt_issuer: Option<String>,
+  jwt_audience: Option<String>,
+  jwt_leeway: Option<u64>,
+  access_token_ttl: Option<u64>,
+  refresh_token_ttl: Option<u64>,
+  remember_me_refresh_token_ttl: Option<u64>,
+  session_max_age: Option<u64>,
+  #[serde(default)]
+  clients: Vec<ClientConfig>,
+  cookie_name: Option<String>,
+  cookie_domain: Option<String>,
+  cookie_path: Option<String>,
+  cookie_same_site: Option<String>,
+  cookie_secure: Option<bool>,
+  cookie_host_prefix: Option<bool>,
+  allowed_origins: Option<Vec<String>>,
+  cors: Option<FileCors>,
+  argon2_memory_kib: Option<u32>,
+  argon2_iterations: Option<u32>,
+  argon2_parallelism: Option<u32>,
+  password_min_length: Option<usize>,
+  password_max_length: Option<usize>,
+  password_required_classes: Option<Vec<String>>,
+  password_deny_list_path: Option<PathBuf>,
+  password_reject_user_info: Option<bool>,
+  breached_passwords_dir: Option<PathBuf>,
+  breached_password_min_count: Option<u64>,
+  admin_api_key: Option<String>,
+}
+
+impl FileConfig {
+  fn read(path: &PathBuf) -> Result<Self, AppError> {
+    let raw = std::fs::read_to_string(path)
+    .map_err(|err| AppError::config_error("CANNOT_READ_CONFIG", Some(format!("{}: {}", path.display(), err))))?;
+
+    toml::from_str(&raw)
+    .map_err(|err| AppError::config_error("INVALID_CONFIG_FILE", Some(format!("{}: {}", path.display(), err))))
+  }
+}
+
+impl AppConfig {
+  /**
+  Resolves the config from (highest precedence first):
+  1. command line flags
+  2. environment variables (a `.env` file is loaded into the environment if present)
+  3. the TOML file given by `--config` / `APP_CONFIG`
+   */
+  pub fn load() -> Result<Self, AppError> {
+    dotenv::dotenv().ok();
+    let args = CliArgs::parse();
+
+    let file = match &args.config {
+      Some(path) => FileConfig::read(path)?,
+      None => FileConfig::default()
+    };
+
+    let mut missing: Vec<&str> = vec![];
+    let mut pick = |key: &'static str, arg: Option<String>, file: Option<String>| -> String {
+      match arg.or(file).filter(|v| !v.trim().is_empty()) {
+        Some(v) => v,
+        None => {
+          missing.push(key);
+          String::new()
+        }
+      }
+    };
+
+    let host_addr = pick("HOST_ADDR", args.host_addr, file.host_addr);
+    let database_url = pick("DATABASE_URL", args.database_url, file.database_url);
+    let refresh_token_secret = pick("REFRESH_TOKEN_SECRET", args.refresh_token_secret, file.refresh_token_secret);
+
+    let jwt_algorithm = match args.jwt_algorithm.or(file.jwt_algorithm) {
+      Some(alg) => alg.parse::<Algorithm>()
+      .map_err(|_| AppError::config_error("INVALID_CONFIG", Some(format!("unsupported JWT_ALGORITHM {}", alg))))?,
+      None => Algorithm::HS256
+    };
+
+    let token_store = match args.token_store.or(file.token_store) {
+      Some(backend) => backend.parse::<TokenStoreBackend>()?,
+      None => TokenStoreBackend::Redis
+    };
+
+    let cookie_same_site = match args.cookie_same_site.or(file.cookie_same_site) {
+      Some(v) => parse_same_site(&v)?,
+      None => SameSite::None
+    };
+    let cookie_host_prefix = args.cookie_host_prefix.or(file.cookie_host_prefix).unwrap_or(false);
+
+    let cli_origins = args.allowed_origins.is_some();
+    let allowed_origins = args.allowed_origins.or(file.allowed_origins).unwrap_or_default().iter()
+    .filter(|v| !v.trim().is_empty())
+    .map(|v| parse_origin(v))
+    .collect::<Result<Vec<String>, AppError>>()?;
+    let mut cors = file.cors.unwrap_or_default();
+    // ALLOWED_ORIGINS given as a flag or in the environment wins over the file
+    if cli_origins {
+      cors.auth.allowed_origins = None;
+    }
+    let cors_auth = CorsConfig::resolve(cors.auth, CorsConfig {
+      allowed_origins,
+      allowed_methods: vec!["GET".into(), "POST".into(), "DELETE".into()],
+      allowed_headers: vec!["authorization".into(), "content-type".into(), "accept".into()],
+      max_age: Some(3600),
+      supports_credentials: true,
+    })?;
+    // these origins are also trusted to refresh with the cookie, * would turn the CSRF origin check off
+    if cors_auth.allowed_origins.iter().any(|o| o == "*") {
+      return Err(AppError::config_error("INVALID_CONFIG", Some("the /auth CORS origins (allowed_origins, [cors.auth]) cannot contain *")));
+    }
+    let cors_public = CorsConfig::resolve(cors.public, CorsConfig {
+      allowed_origins: vec!["*".into()],
+      allowed_methods: vec!["GET".into()],
+      allowed_headers: vec![],
+      max_age: Some(3600),
+      supports_credentials: false,
+    })?;
+
+    let password_required_classes = args.password_required_classes.or(file.password_required_classes).unwrap_or_default().iter()
+    .filter(|v| !v.trim().is_empty())
+    .map(|v| v.parse::<CharClass>())
+    .collect::<Result<Vec<CharClass>, AppError>>()?;
+
+    let config = Self {
+      host_addr,
+      database_url,
+      token_store,
+      redis_url: args.redis_url.or(file.redis_url).filter(|v| !v.trim().is_empty()),
+      access_token_secret: args.access_token_secret.or(file.access_token_secret).filter(|v| !v.trim().is_empty()),
+      refresh_token_secret,
+      jwt_algorithm,
+      jwt_kid: args.jwt_kid.or(file.jwt_kid).unwrap_or_else(|| "primary".into()),
+      jwt_private_key_path: args.jwt_private_key_path.or(file.jwt_private_key_path),
+      jwt_public_key_path: args.jwt_public_key_path.or(file.jwt_public_key_path),
+      jwt_keys: file.jwt_keys,
+      refresh_token_kid: args.refresh_token_kid.or(file.refresh_token_kid).unwrap_or_else(|| "refresh".into()),
+      refresh_keys: file.refresh_keys,
+      jwt_issuer: args.jwt_issuer.or(file.jwt_issuer).unwrap_or_else(|| "userauth".into()),
+      jwt_audience: args.jwt_audience.or(file.jwt_audience).unwrap_or_else(|| "userauth".into()),
+      jwt_leeway: args.jwt_leeway.or(file.jwt_leeway).unwrap_or(30),
+      access_token_ttl: args.access_token_ttl.or(file.access_token_ttl).unwrap_or(300),
+      refresh_token_ttl: args.refresh_token_ttl.or(file.refresh_token_ttl).unwrap_or(172800),
+      remember_me_refresh_token_ttl: args.remember_me_refresh_token_ttl.or(file.remember_me_refresh_token_ttl).unwrap_or(2592000),
+      session_max_age: args.session_max_age.or(file.session_max_age).unwrap_or(7776000),
+      clients: file.clients,
+      cookie_name: args.cookie_name.or(file.cookie_name).unwrap_or_else(|| "jwt".into()),
+      cookie_domain: args.cookie_domain.or(file.cookie_domain).filter(|v| !v.trim().is_empty()),
+      cookie_path: args.cookie_path.or(file.cookie_path).unwrap_or_else(|| if cookie_host_prefix { "/" } else { "/auth" }.into()),
+      cookie_same_site,
+      cookie_secure: args.cookie_secure.or(file.cookie_secure).unwrap_or(true),
+      cookie_host_prefix,
+      cors_auth,
+      cors_public,
+      argon2_memory_kib: args.argon2_memory_kib.or(file.argon2_memory_kib).unwrap_or(19456),
+      argon2_iterations: args.argon2_iterations.or(file.argon2_iterations).unwrap_or(2),
+      argon2_parallelism: args.argon2_parallelism.or(file.argon2_parallelism).unwrap_or(1),
+      password_min_length: args.password_min_length.or(file.password_min_length).unwrap_or(8),
+      password_max_length: args.password_max_length.or(file.password_max_length).unwrap_or(128),
+      password_required_classes,
+      password_deny_list_path: args.password_deny_list_path.or(file.password_deny_list_path),
+      password_reject_user_info: args.password_reject_user_info.or(file.password_reject_user_info).unwrap_or(true),
+      breached_passwords_dir: args.breached_passwords_dir.or(file.breached_passwords_dir),
+      breached_password_min_count: args.breached_password_min_count.or(file.breached_password_min_count).unwrap_or(1),
+      admin_api_key: args.admin_api_key.or(file.admin_api_key).filter(|v| !v.trim().is_empty()),
+    };
+
+    if config.token_store == TokenStoreBackend::Redis && config.redis_url.is_none() {
+      missing.push("REDIS_URL");
+    }
+
+    if is_symmetric(config.jwt_algorithm) {
+      if config.access_token_secret.is_none() {
+        missing.push("ACCESS_TOKEN_SECRET");
+      }
+    } else {
+      if config.jwt_private_key_path.is_none() {
+        missing.push("JWT_PRIVATE_KEY_PATH");
+      }
+      if config.jwt_public_key_path.is_none() {
+        missing.push("JWT_PUBLIC_KEY_PATH");
+      }
+    }
+
+    let mut lifetimes = [config.access_token_ttl, config.refresh_token_ttl, config.remember_me_refresh_token_ttl, config.session_max_age].into_iter()
+    .chain(config.clients.iter().flat_map(|c| [c.access_token_ttl, c.refresh_token_ttl, c.remember_me_refresh_token_ttl, c.session_max_age].into_iter().flatten()));
+    if lifetimes.any(|ttl| ttl == 0) {
+      return Err(AppError::config_error("INVALID_CONFIG", Some("token lifetimes must be at least one second")));
+    }
+
+    // browsers drop cookies breaking these rules
+    if config.cookie_same_site == SameSite::None && !config.cookie_secure {
+      return Err(AppError::config_error("INVALID_CONFIG", Some("cookie_same_site none requires cookie_secure")));
+    }
+    if config.cookie_host_prefix && (!config.cookie_secure || config.cookie_domain.is_some() || config.cookie_path != "/") {
+      return Err(AppError::config_error("INVALID_CONFIG", Some("cookie_host_prefix requires cookie_secure, no cookie_domain and cookie_path /")));
+    }
+
+    if config.password_min_length == 0 || config.password_min_length > config.password_max_length {
+      return Err(AppError::config_error("INVALID_CONFIG", Some("password_min_length must be at least 1 and at most password_max_length")));
+    }
+    if config.breached_password_min_count == 0 {
+      return Err(AppError::config_error("INVALID_CONFIG", Some("breached_password_min_count must be at least 1")));
+    }
+
+    if !missing.is_empty() {
+      let keys: Vec<String> = missing.iter()
+      .map(|key| format!("{} (--{} / {})", key, key.to_lowercase().replace('_', "-"), key.to_lowercase()))
+      .collect();
+      return Err(AppError::config_error("MISSING_CONFIG", Some(format!("missing required config keys: {}", keys.join(", ")))));
+    }
+
+    Ok(config)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn parses_origins() {
+    assert_eq!(parse_origin("https://app.example.com").unwrap(), "https://app.example.com");
+    assert_eq!(parse_origin(" http://localhost:5173/ ").unwrap(), "http://localhost:5173");
+    assert_eq!(parse_origin("https://*.example.com").unwrap(), "https://*.example.com");
+    assert_eq!(parse_origin("https://*.example.com:8443").unwrap(), "https://*.example.com:8443");
+    assert_eq!(parse_origin("*").unwrap(), "*");
+  }
+
+  #[tokio::test]
+  async fn rejects_invalid_origins() {
+    for origin in ["app.example.com", "ftp://example.com", "https://example.com/app", "https://", "https://*example.com", "https://a.*.example.com", "https://*.*.example.com", "https://*"] {
+      assert!(parse_origin(origin).is_err(), "{} should be rejected", origin);
+    }
+  }
+
+  #[tokio::test]
+  async fn rejects_any_origin_with_credentials() {
+    let file = FileCorsConfig {
+      allowed_origins: Some(vec!["*".into()]),
+      supports_credentials: Some(true),
+      ..Default::default()
+    };
+    let defaults = CorsConfig {
+      allowed_origins: vec![],
+      allowed_methods: vec!["GET".into()],
+      allowed_headers: vec![],
+      max_age: None,
+      supports_credentials: false,
+    };
+    assert!(CorsConfig::resolve(file, defaults).is_err());
+  }
 }
@@ -1,4 +1,6 @@
 pub mod user;
 pub mod prelude;
 pub use prelude::*;
-pub mod redis;
\ No newline at end of file
+pub mod redis;
+pub mod memory;
+pub mod token_store;
\ No newline at end of file
@@ -0,0 +1,330 @@
+use std::{collections::{HashMap, HashSet}, sync::{Arc, Mutex, MutexGuard}, time::Duration};
+
+use async_trait::async_trait;
+
+use crate::{errors::{AppError, TAppError}, config::{AppConfig, FromConfig}};
+
+use super::token_store::TokenStore;
+
+const SWEEP_INTERVAL_SECS: u64 = 60;
+
+struct Entry {
+  value: String,
+  expires_at: Option<u64>
+}
+
+impl Entry {
+  fn is_expired(&self, now: u64) -> bool {
+    self.expires_at.map(|at| at <= now).unwrap_or(false)
+  }
+}
+
+#[derive(Default)]
+struct Inner {
+  entries: HashMap<String, Entry>,
+  user_index: HashMap<String, HashSet<String>>
+}
+
+/**
+ * In-process token store for single node deployments, nothing survives a restart.
+ * Expired entries are hidden on read and swept periodically.
+ */
+pub struct MemoryTokenStore {
+  logger: slog::Logger,
+  inner: Arc<Mutex<Inner>>
+}
+
+impl MemoryTokenStore {
+  pub fn new(logger: slog::Logger) -> Self {
+    let slf = Self {
+      logger,
+      inner: Arc::new(Mutex::new(Inner::default()))
+    };
+    slf.spawn_sweeper();
+    slf
+  }
+
+  fn lock(&self) -> Result<MutexGuard<'_, Inner>, AppError> {
+    self.inner.lock()
+    .map_err(|e| AppError::crit_error(self.logger.clone(), "RTS_VAR_POIS", Some("memory token store poisoned")))
+  }
+
+  fn spawn_sweeper(&self) {
+    let inner = Arc::downgrade(&self.inner);
+    tokio::spawn(async move {
+      let mut interval = tokio::time::interval(Duration::from_secs(SWEEP_INTERVAL_SECS));
+      loop {
+        interval.tick().await;
+        // the store has been dropped
+        let inner = match inner.upgrade() {
+          Some(v) => v,
+          None => break
+        };
+        if let Ok(mut inner) = inner.lock() {
+          inner.sweep(jsonwebtoken::get_current_timestamp());
+        };
+      }
+    });
+  }
+}
+
+impl Inner {
+  fn sweep(&mut self, now: u64) {
+    self.entries.retain(|_, entry| !entry.is_expired(now));
+    let entries = &self.entries;
+    self.user_index.retain(|_, keys| {
+      keys.retain(|key| entries.contains_key(key));
+      !keys.is_empty()
+    });
+  }
+}
+
+#[async_trait]
+impl TokenStore for MemoryTokenStore {
+  async fn set(&self, user_id: Option<&str>, key: &str, value: &str, ttl: Option<u64>) -> Result<(), AppError> {
+    let mut inner = self.lock()?;
+    inner.entries.insert(key.into(), Entry {
+      value: value.into(),
+      expires_at: ttl.map(|ttl| jsonwebtoken::get_current_timestamp() + ttl)
+    });
+    if let Some(user_id) = user_id {
+      inner.user_index.entry(user_id.into()).or_default().insert(key.into());
+    }
+    Ok(())
+  }
+
+  async fn get(&self, key: &str) -> Result<Option<String>, AppError> {
+    let now = jsonwebtoken::get_current_timestamp();
+    let inner = self.lock()?;
+    Ok(
+      inner.entries.get(key)
+      .filter(|entry| !entry.is_expired(now))
+      .map(|entry| entry.value.clone())
+    )
+  }
+
+  async fn delete(&self, user_id: Option<&str>, key: &str) -> Result<(), AppError> {
+    let mut inner = self.lock()?;
+    inner.entries.remove(key);
+    if let Some(keys) = user_id.and_then(|user_id| inner.user_index.get_mut(user_id)) {
+      keys.remove(key);
+    }
+    Ok(())
+  }
+
+  async fn list_by_user(&self, user_id: &str) -> Result<Vec<String>, AppError> {
+    let now = jsonwebtoken::get_current_timestamp();
+    let inner = self.lock()?;
+    let keys = match inner.user_index.get(user_id) {
+      Some(v) => v,
+      None => return Ok(vec![])
+    };
+    Ok(
+      keys.iter()
+      .filter(|key| inner.entries.get(*key).map(|entry| !entry.is_expired(now)).unwrap_or(false))
+      .cloned()
+      .collect()
+    )
+  }
+
+  async fn compare_and_swap(&self, user_id: Option<&str>, key: &str, expected: &str, value: &str, ttl: Option<u64>) -> Result<bool, AppError> {
+    let now = jsonwebtoken::get_current_timestamp();
+    // one lock for the comparison and the write
+    let mut inner = self.lock()?;
+    let matches = inner.entries.get(key)
+    .map(|entry| !entry.is_expired(now) && entry.value == expected)
+    .unwrap_or(false);
+    if !matches {
+      return Ok(false);
+    }
+
+    inner.entries.insert(key.into(), Entry {
+      value: value.into(),
+      expires_at: ttl.map(|ttl| now + ttl)
+    });
+    if let Some(user_id) = user_id {
+      inner.user_index.entry(user_id.into()).or_default().insert(key.into());
+    }
+    Ok(true)
+  }
+
+  async fn expire(&self, key: &str, ttl: u64) -> Result<(), AppError> {
+    let mut inner = self.lock()?;
+    if let Some(entry) = inner.entries.get_mut(key) {
+      entry.expires_at = Some(jsonwebtoken::get_current_timestamp() + ttl);
+    }
+    Ok(())
+  }
+}
+
+impl FromConfig for MemoryTokenStore {
+  type Item = Self;
+  fn from_config(logger: slog::Logger, config: &AppConfig) -> Self::Item {
+    info!(logger, "> Using the in-memory token store, sessions are lost on restart and not shared between instances");
+    Self::new(logger)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{db::token_store::StoredSession, models::session::SessionInfo};
+
+  fn store() -> MemoryTokenStore {
+    MemoryTokenStore::new(slog::Logger::root(slog::Discard, o!()))
+  }
+
+  fn session(token: &str, device_name: &str) -> StoredSession {
+    StoredSession {
+      token: token.into(),
+      info: SessionInfo {
+        device_name: Some(device_name.into()),
+        ..SessionInfo::default()
+      }
+    }
+  }
+
+  fn in_a_minute() -> u64 {
+    jsonwebtoken::get_current_timestamp() + 60
+  }
+
+  #[tokio::test]
+  async fn expired_entries_are_hidden_then_swept() {
+    let store = store();
+    store.set(Some("u1"), "gone", "1", Some(0)).await.unwrap();
+    store.set(Some("u1"), "live", "1", Some(60)).await.unwrap();
+    store.set(None, "forever", "1", None).await.unwrap();
+
+    assert_eq!(store.get("gone").await.unwrap(), None);
+    assert_eq!(store.get("live").await.unwrap().as_deref(), Some("1"));
+    assert_eq!(store.list_by_user("u1").await.unwrap(), vec!["live".to_string()]);
+
+    store.lock().unwrap().sweep(jsonwebtoken::get_current_timestamp() + 120);
+    let inner = store.lock().unwrap();
+    assert!(!inner.entries.contains_key("gone") && !inner.entries.contains_key("live"));
+    assert!(inner.entries.contains_key("forever"));
+    assert!(inner.user_index.is_empty());
+  }
+
+  #[tokio::test]
+  async fn expire_shortens_an_entry() {
+    let store = store();
+    store.set(None, "key", "1", Some(60)).await.unwrap();
+    store.expire("key", 0).await.unwrap();
+    assert_eq!(store.get("key").await.unwrap(), None);
+  }
+
+  #[tokio::test]
+  async fn keys_are_indexed_per_user() {
+    let store = store();
+    store.set(Some("u1"), "a", "1", Some(60)).await.unwrap();
+    store.set(Some("u1"), "b", "1", Some(60)).await.unwrap();
+    store.set(Some("u2"), "c", "1", Some(60)).await.unwrap();
+    store.set(None, "d", "1", Some(60)).await.unwrap();
+
+    let mut keys = store.list_by_user("u1").await.unwrap();
+    keys.sort();
+    assert_eq!(keys, vec!["a".to_string(), "b".to_string()]);
+
+    store.delete(Some("u1"), "a").await.unwrap();
+    assert_eq!(store.list_by_user("u1").await.unwrap(), vec!["b".to_string()]);
+    assert_eq!(store.list_by_user("u2").await.unwrap(), vec!["c".to_string()]);
+    assert!(store.list_by_user("u3").await.unwrap().is_empty());
+  }
+
+  #[tokio::test]
+  async fn sessions_round_trip() {
+    let store = store();
+    store.set_session("u1", "s1", &session("tok1", "laptop"), in_a_minute(), None).await.unwrap();
+    store.set_session("u1", "s2", &session("tok2", "phone"), in_a_minute(), None).await.unwrap();
+
+    let stored = store.get_session("u1", "s1").await.unwrap().unwrap();
+    assert_eq!(stored.token, "tok1");
+    assert_eq!(stored.info.device_name.as_deref(), Some("laptop"));
+    assert!(store.get_session("u2", "s1").await.unwrap().is_none());
+
+    let mut sessions: Vec<String> = store.list_sessions("u1").await.unwrap().into_iter().map(|(sid, _)| sid).collect();
+    sessions.sort();
+    assert_eq!(sessions, vec!["s1".to_string(), "s2".to_string()]);
+
+    store.delete_session("u1", "s2").await.unwrap();
+    assert_eq!(store.list_sessions("u1").await.unwrap().len(), 1);
+  }
+
+  #[tokio::test]
+  async fn sessions_rotate_only_from_the_stored_value() {
+    let store = store();
+    let first = session("tok1", "laptop");
+    store.set_session("u1", "s1", &first, in_a_minute(), None).await.unwrap();
+    let raw = serde_json::to_string(&first).unwrap();
+
+    store.set_session("u1", "s1", &session("tok2", "laptop"), in_a_minute(), Some(&raw)).await.unwrap();
+    // the value was already rotated away from
+    let err = store.set_session("u1", "s1", &session("tok3", "laptop"), in_a_minute(), Some(&raw)).await.unwrap_err();
+    assert_eq!(err.error_code, "REUSED_REFRESH_TOKEN");
+    assert_eq!(store.get_session("u1", "s1").await.unwrap().unwrap().token, "tok2");
+
+    assert!(!store.compare_and_swap(None, "missing", "", "1", None).await.unwrap());
+  }
+
+  #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+  async fn concurrent_swaps_have_one_winner() {
+    let store = Arc::new(store());
+    store.set(Some("u1"), "key", "old", Some(60)).await.unwrap();
+
+    let swaps: Vec<_> = (0..8).map(|i| {
+      let store = store.clone();
+      tokio::spawn(async move { store.compare_and_swap(Some("u1"), "key", "old", &format!("new{}", i), Some(60)).await.unwrap() })
+    }).collect();
+    let mut won = 0;
+    for swap in swaps {
+      won += swap.await.unwrap() as usize;
+    }
+    assert_eq!(won, 1);
+  }
+
+  #[tokio::test]
+  async fn revoked_families_are_remembered() {
+    let store = store();
+    store.set_session("u1", "s1", &session("tok1", "laptop"), in_a_minute(), None).await.unwrap();
+    assert!(!store.is_family_revoked("u1", "s1").await.unwrap());
+
+    store.revoke_family("u1", "s1", 60).await.unwrap();
+    assert!(store.is_family_revoked("u1", "s1").await.unwrap());
+    assert!(!store.is_family_revoked("u1", "s2").await.unwrap());
+    assert!(store.get_session("u1", "s1").await.unwrap().is_none());
+    assert!(store.list_sessions("u1").await.unwrap().is_empty());
+  }
+
+  #[tokio::test]
+  async fn revoking_user_sessions_revokes_each_family() {
+    let store = store();
+    store.set_session("u1", "s1", &session("tok1", "laptop"), in_a_minute(), None).await.unwrap();
+    store.set_session("u1", "s2", &session("tok2", "phone"), in_a_minute(), None).await.unwrap();
+
+    assert_eq!(store.revoke_user_sessions("u1", 60).await.unwrap(), 2);
+    assert!(store.is_family_revoked("u1", "s1").await.unwrap());
+    assert!(store.is_family_revoked("u1", "s2").await.unwrap());
+    assert!(store.list_sessions("u1").await.unwrap().is_empty());
+  }
+
+  #[tokio::test]
+  async fn access_token_status() {
+    let store = store();
+    assert_eq!(store.access_token_status("u1", Some("s1"), "jti1").await.unwrap(), (false, None));
+
+    store.deny_access_token("jti1", 60).await.unwrap();
+    assert_eq!(store.access_token_status("u1", Some("s1"), "jti1").await.unwrap(), (true, None));
+    assert_eq!(store.access_token_status("u1", Some("s1"), "jti2").await.unwrap(), (false, None));
+
+    // every access token of a revoked session is denied
+    store.revoke_family("u1", "s1", 60).await.unwrap();
+    assert_eq!(store.access_token_status("u1", Some("s1"), "jti2").await.unwrap(), (true, None));
+    assert_eq!(store.access_token_status("u1", Some("s2"), "jti3").await.unwrap(), (false, None));
+    assert_eq!(store.access_token_status("u1", None, "jti3").await.unwrap(), (false, None));
+
+    store.revoke_access_tokens_before("u1", 1234, 60).await.unwrap();
+    assert_eq!(store.access_token_status("u1", None, "jti3").await.unwrap(), (false, Some(1234)));
+    assert_eq!(store.access_token_status("u2", None, "jti3").await.unwrap(), (false, None));
+  }
+}
@@ -3,7 +3,7 @@ use std::{time::Duration, sync::{Arc, RwLock, RwLockWriteGuard, RwLockReadGuard}
 use slog::Logger;
 use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
 
-use crate::errors::{AppError, TAppError};
+use crate::{errors::{AppError, TAppError}, config::AppConfig};
 
 pub type DbRef = Arc<RwLock<Db>>;
 
@@ -15,8 +15,8 @@ pub struct Db {
 const DBCONN_TIMEOUT_MS: u64 = 2500;
 
 impl Db {
-  pub async fn connect_from_env(logger: Logger) -> Result<Self, AppError> {
-    Self::connect(logger.clone(), dotenv!("DATABASE_URL")).await
+  pub async fn connect_from_config(logger: Logger, config: &AppConfig) -> Result<Self, AppError> {
+    Self::connect(logger.clone(), &config.database_url).await
   }
 
   async fn connect(logger: Logger, url: &'_ str) -> Result<Self, AppError> {
@@ -54,4 +54,12 @@ impl Db {
 
     Ok(r)
   }
+
+  /**
+   * clones the pool and logger out of the lock, so no guard is held across an await
+   */
+  pub fn get_handles(db_ref: &DbRef) -> Result<(Pool<Postgres>, Logger), AppError> {
+    let r = Self::get_readable(db_ref)?;
+    Ok((r.pool.clone(), r.logger.clone()))
+  }
 }
\ No newline at end of file
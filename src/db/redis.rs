@@ -1,71 +1,214 @@
-use std::sync::{RwLock, Arc, RwLockWriteGuard, RwLockReadGuard};
+use async_trait::async_trait;
+use redis::aio::ConnectionManager;
 
-use redis::{Connection, Commands};
+use crate::{errors::{AppError, TAppError}, config::AppConfig};
 
-use crate::{errors::{AppError, TAppError}, auth::tokens::JWTClaim};
+use super::token_store::TokenStore;
 
-pub type RefreshTokenStore = Arc<RwLock<RefreshTokenRedis>>;
+/**
+ * reconnect backoff of the connection manager, retry n waits up to `FACTOR * BASE^n` ms
+ */
+const RECONNECT_BACKOFF_BASE: u64 = 2;
+const RECONNECT_BACKOFF_FACTOR: u64 = 100;
+const RECONNECT_RETRIES: usize = 6;
+
+/**
+ * KEYS[1] the key, KEYS[2] the user index if any, ARGV expected value, new value, ttl ('' for none).
+ * Redis runs scripts atomically, so the comparison and the write cannot interleave with another swap.
+ * The index is kept as in `set`: it lives as long as its longest lived key
+ */
+const COMPARE_AND_SWAP_SCRIPT: &str = r#"
+if redis.call('GET', KEYS[1]) ~= ARGV[1] then
+  return 0
+end
+if ARGV[3] == '' then
+  redis.call('SET', KEYS[1], ARGV[2])
+else
+  redis.call('SET', KEYS[1], ARGV[2], 'EX', ARGV[3])
+end
+if KEYS[2] then
+  redis.call('SADD', KEYS[2], KEYS[1])
+  local ttl = redis.call('TTL', KEYS[2])
+  if ARGV[3] == '' then
+    redis.call('PERSIST', KEYS[2])
+  elseif ttl == -2 or (ttl >= 0 and ttl < tonumber(ARGV[3])) then
+    redis.call('EXPIRE', KEYS[2], ARGV[3])
+  end
+end
+return 1
+"#;
 
 pub struct RefreshTokenRedis {
   pub logger: slog::Logger,
-  pub client: redis::Client
+  /**
+   * one multiplexed connection shared by all workers, re-established in the background when it drops
+   */
+  conn: ConnectionManager,
+  /**
+   * hashed once, invoked by its sha and loaded on first use
+   */
+  compare_and_swap: redis::Script
 }
 
 impl RefreshTokenRedis {
-  pub fn init_client(logger: slog::Logger, host: String) -> Result<Self, AppError> {
-    info!(logger, "> Initialized redis client");
-    let client = redis::Client::open(host)
+  pub async fn connect_from_config(logger: slog::Logger, config: &AppConfig) -> Result<Self, AppError> {
+    let url = match &config.redis_url {
+      Some(v) => v.clone(),
+      None => return Err(AppError::config_error("MISSING_CONFIG", Some("REDIS_URL is required for the redis token store")))
+    };
+    Self::connect(logger, url).await
+  }
+
+  async fn connect(logger: slog::Logger, url: String) -> Result<Self, AppError> {
+    info!(logger, "> Connecting to redis... (up to {} retries)", RECONNECT_RETRIES);
+    let client = redis::Client::open(url)
     .map_err(|_| AppError::crit_error(logger.clone(), "CANNOT_INIT_REDIS", Some("unable to initialize redis client")))?;
 
-    let slf = RefreshTokenRedis{
+    let conn = ConnectionManager::new_with_backoff(client, RECONNECT_BACKOFF_BASE, RECONNECT_BACKOFF_FACTOR, RECONNECT_RETRIES)
+    .await
+    .map_err(|_| AppError::crit_error(logger.clone(), "CANNOT_CONN_REDIS", Some("unable to connect to redis server")))?;
+
+    Ok(Self {
       logger,
-      client
-    };
-    
-    Ok(slf)
+      conn,
+      compare_and_swap: redis::Script::new(COMPARE_AND_SWAP_SCRIPT)
+    })
   }
 
-  pub fn get_conn(&self) -> Result<Connection, AppError> {
-    self.client.get_connection()
-    .map_err(|e| AppError::crit_error(self.logger.clone(), "CANNOT_CONN_REDIS", Some("unable to connect to redis server")))
+  /**
+   * handles are cheap clones of the shared connection
+   */
+  fn get_conn(&self) -> ConnectionManager {
+    self.conn.clone()
   }
 
-  pub fn init_from_env(logger: slog::Logger) -> Result<Self, AppError> {
-    RefreshTokenRedis::init_client(logger, dotenv!("REDIS_URL").into())
+  /**
+   * set of the keys indexed under a user
+   */
+  fn user_index_key(user_id: &str) -> String {
+    format!("rt:idx:{}", user_id)
   }
 
-  pub fn get_writable(logger: slog::Logger, rts: &RefreshTokenStore) -> Result<RwLockWriteGuard<'_, Self>, AppError> {
-    rts.write()
-    .map_err(|e| AppError::crit_error(logger, "RTS_VAR_POIS", Some("rts variable poisoned")))
+  fn redis_error(&self, err: redis::RedisError) -> AppError {
+    AppError::crit_error(self.logger.clone(), "REDIS_ERROR", Some(err.to_string()))
   }
+}
 
-  pub fn get_readable(logger: slog::Logger, rts: &RefreshTokenStore) -> Result<RwLockReadGuard<'_, Self>, AppError> {
-    rts.read()
-    .map_err(|e| AppError::crit_error(logger, "RTS_VAR_POIS", Some("rts variable poisoned")))
-  }
+#[async_trait]
+impl TokenStore for RefreshTokenRedis {
+  async fn set(&self, user_id: Option<&str>, key: &str, value: &str, ttl: Option<u64>) -> Result<(), AppError> {
+    let mut conn = self.get_conn();
+    let index_key = user_id.map(Self::user_index_key);
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    if let Some(index_key) = &index_key {
+      pipe.ttl(index_key);
+    }
+    match ttl {
+      Some(ttl) => pipe.set_ex(key, value, ttl as usize).ignore(),
+      None => pipe.set(key, value).ignore()
+    };
+    if let Some(index_key) = &index_key {
+      pipe.sadd(index_key, key).ignore();
+    }
 
-  /**
-   * refreshes all tokens, returns new access token and new refresh token
-   */
-  pub fn refresh_tokens(logger: slog::Logger, rts: &RefreshTokenStore, old_reftok: String) -> Result<(String, String), AppError> {
-    let user_id = JWTClaim::verify_refresh_token(old_reftok.clone())?.to_string();
-    let dbread = RefreshTokenRedis::get_readable(logger.clone(), rts)?;
-
-    let mut conn = dbread.get_conn()?;
-    let curr_reftok: String = match conn.get(user_id.clone())
-    .map_err(|err| AppError::crit_error(logger.clone(), "CANNOT_GET_REDIS", Some(err.to_string())))? {
-      Some(v) => v,
-      None => return Err(AppError::auth_error(401, "NO_REFRESH_TOKEN", Some("you have an invalid refresh token")))
+    let index_ttl: Vec<i64> = pipe.query_async(&mut conn)
+    .await
+    .map_err(|err| self.redis_error(err))?;
+
+    // the index lives as long as its longest lived key
+    let (index_key, index_ttl) = match (index_key, index_ttl.first()) {
+      (Some(k), Some(t)) => (k, *t),
+      _ => return Ok(())
+    };
+    let cmd = match ttl {
+      // -2 is a new index, -1 one that already never expires
+      Some(ttl) if index_ttl == -2 || (index_ttl >= 0 && (index_ttl as u64) < ttl) => redis::cmd("EXPIRE").arg(&index_key).arg(ttl).to_owned(),
+      Some(_) => return Ok(()),
+      None => redis::cmd("PERSIST").arg(&index_key).to_owned()
     };
-  
+    cmd.query_async::<_, ()>(&mut conn)
+    .await
+    .map_err(|err| self.redis_error(err))
+  }
+
+  async fn get(&self, key: &str) -> Result<Option<String>, AppError> {
+    let mut conn = self.get_conn();
+    redis::cmd("GET")
+    .arg(key)
+    .query_async(&mut conn)
+    .await
+    .map_err(|err| self.redis_error(err))
+  }
 
-    if curr_reftok != old_reftok {
-      return Err(AppError::auth_error(401, "INVALID_REFRESH_TOKEN", Some("your refresh token has been cycled")));
+  async fn delete(&self, user_id: Option<&str>, key: &str) -> Result<(), AppError> {
+    let mut conn = self.get_conn();
+    let mut pipe = redis::pipe();
+    pipe.atomic().del(key).ignore();
+    if let Some(user_id) = user_id {
+      pipe.srem(Self::user_index_key(user_id), key).ignore();
     }
 
-    let new_reftok = JWTClaim::refresh_token(logger.clone(), rts, user_id.clone())?;
-    let new_acctok = JWTClaim::access_token(user_id)?;
+    pipe.query_async::<_, ()>(&mut conn)
+    .await
+    .map_err(|err| self.redis_error(err))
+  }
 
-    Ok((new_acctok, new_reftok))
+  async fn list_by_user(&self, user_id: &str) -> Result<Vec<String>, AppError> {
+    let mut conn = self.get_conn();
+    let index_key = Self::user_index_key(user_id);
+    let keys: Vec<String> = redis::cmd("SMEMBERS")
+    .arg(&index_key)
+    .query_async(&mut conn)
+    .await
+    .map_err(|err| self.redis_error(err))?;
+    if keys.is_empty() {
+      return Ok(keys);
+    }
+
+    let mut pipe = redis::pipe();
+    for key in &keys {
+      pipe.exists(key);
+    }
+    let exists: Vec<bool> = pipe.query_async(&mut conn)
+    .await
+    .map_err(|err| self.redis_error(err))?;
+
+    // keys that expired on their own are still in the index, prune them
+    let (live, expired): (Vec<_>, Vec<_>) = keys.into_iter().zip(exists).partition(|(_, exists)| *exists);
+    if !expired.is_empty() {
+      let expired: Vec<String> = expired.into_iter().map(|(key, _)| key).collect();
+      redis::cmd("SREM")
+      .arg(&index_key)
+      .arg(expired)
+      .query_async::<_, ()>(&mut conn)
+      .await
+      .map_err(|err| self.redis_error(err))?;
+    }
+
+    Ok(live.into_iter().map(|(key, _)| key).collect())
   }
-}
\ No newline at end of file
+
+  async fn compare_and_swap(&self, user_id: Option<&str>, key: &str, expected: &str, value: &str, ttl: Option<u64>) -> Result<bool, AppError> {
+    let mut conn = self.get_conn();
+    let mut invocation = self.compare_and_swap.key(key);
+    if let Some(user_id) = user_id {
+      invocation.key(Self::user_index_key(user_id));
+    }
+    let ttl = ttl.map(|ttl| ttl.to_string()).unwrap_or_default();
+    invocation.arg(expected).arg(value).arg(ttl)
+    .invoke_async(&mut conn)
+    .await
+    .map_err(|err| self.redis_error(err))
+  }
+
+  async fn expire(&self, key: &str, ttl: u64) -> Result<(), AppError> {
+    let mut conn = self.get_conn();
+    redis::cmd("EXPIRE")
+    .arg(key)
+    .arg(ttl)
+    .query_async::<_, ()>(&mut conn)
+    .await
+    .map_err(|err| self.redis_error(err))
+  }
+}
@@ -0,0 +1,278 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::{
+  errors::{AppError, TAppError},
+  auth::{tokens::{JWTClaim, TokenPair}, keys::KeyRingRef, lifetimes::LifetimePolicy},
+  config::{AppConfig, FromConfig, TokenStoreBackend},
+  models::session::{ClientInfo, SessionInfo}
+};
+
+use super::{DbRef, redis::RefreshTokenRedis, memory::MemoryTokenStore, user::{fetch_by_id, fetch_identity}};
+
+pub type RefreshTokenStore = Arc<dyn TokenStore>;
+
+/**
+ * a session's current refresh token and its metadata, stored as json under the session key
+ */
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredSession {
+  pub token: String,
+  #[serde(flatten)]
+  pub info: SessionInfo
+}
+
+impl StoredSession {
+  fn parse(value: String) -> Self {
+    match serde_json::from_str(&value) {
+      Ok(v) => v,
+      // sessions stored before metadata was recorded hold the bare token
+      Err(_) => Self {
+        token: value,
+        info: SessionInfo::default()
+      }
+    }
+  }
+}
+
+/**
+ * Key/value storage for refresh tokens and revocation state.
+ * Backends only implement the primitives, the session logic is shared in the provided methods.
+ */
+#[async_trait]
+pub trait TokenStore: Send + Sync {
+  /**
+   * stores `value`, indexed under `user_id` when given, expiring after `ttl` seconds when given
+   */
+  async fn set(&self, user_id: Option<&str>, key: &str, value: &str, ttl: Option<u64>) -> Result<(), AppError>;
+
+  async fn get(&self, key: &str) -> Result<Option<String>, AppError>;
+
+  async fn delete(&self, user_id: Option<&str>, key: &str) -> Result<(), AppError>;
+
+  /**
+   * keys currently indexed under `user_id`, expired keys are left out
+   */
+  async fn list_by_user(&self, user_id: &str) -> Result<Vec<String>, AppError>;
+
+  async fn expire(&self, key: &str, ttl: u64) -> Result<(), AppError>;
+
+  /**
+   * like `set`, but only if `key` still holds `expected`, returns whether the value was replaced.
+   * The comparison and the write are atomic, of concurrent swaps from the same value exactly one wins
+   */
+  async fn compare_and_swap(&self, user_id: Option<&str>, key: &str, expected: &str, value: &str, ttl: Option<u64>) -> Result<bool, AppError>;
+
+  /**
+   * stores the current refresh token of a session with its metadata and indexes the session under its user,
+   * the entry expires together with the token (`exp`).
+   * With `replaces` (the stored value being rotated out) the write only happens if the session still holds it,
+   * otherwise another rotation won and this fails with 401 `REUSED_REFRESH_TOKEN`
+   */
+  async fn set_session(&self, user_id: &str, sid: &str, session: &StoredSession, exp: u64, replaces: Option<&str>) -> Result<(), AppError> {
+    let ttl = exp.saturating_sub(jsonwebtoken::get_current_timestamp()).max(1);
+    let value = serde_json::to_string(session)
+    .map_err(|err| AppError::auth_error(500, "CANNOT_ENC_SESSION", Some(err.to_string())))?;
+    let key = session_key(user_id, sid);
+    let current = match replaces {
+      Some(v) => v,
+      None => return self.set(Some(user_id), &key, &value, Some(ttl)).await
+    };
+
+    match self.compare_and_swap(Some(user_id), &key, current, &value, Some(ttl)).await? {
+      true => Ok(()),
+      false => Err(AppError::auth_error(401, "REUSED_REFRESH_TOKEN", Some("your refresh token has already been used")))
+    }
+  }
+
+  async fn get_session(&self, user_id: &str, sid: &str) -> Result<Option<StoredSession>, AppError> {
+    Ok(self.get(&session_key(user_id, sid)).await?.map(StoredSession::parse))
+  }
+
+  /**
+   * live sessions of a user by session id
+   */
+  async fn list_sessions(&self, user_id: &str) -> Result<Vec<(String, SessionInfo)>, AppError> {
+    let prefix = session_key(user_id, "");
+    let mut sessions = vec![];
+    for key in self.list_by_user(user_id).await? {
+      let sid = match key.strip_prefix(&prefix) {
+        Some(v) => v.to_string(),
+        None => continue
+      };
+      // the session may have ended since it was listed
+      if let Some(value) = self.get(&key).await? {
+        sessions.push((sid, StoredSession::parse(value).info));
+      }
+    }
+    Ok(sessions)
+  }
+
+  async fn delete_session(&self, user_id: &str, sid: &str) -> Result<(), AppError> {
+    self.delete(Some(user_id), &session_key(user_id, sid)).await
+  }
+
+  /**
+   * revokes every session of a user with `revoke_family`, returns how many there were
+   */
+  async fn revoke_user_sessions(&self, user_id: &str, ttl: u64) -> Result<usize, AppError> {
+    let sessions = self.list_sessions(user_id).await?;
+    for (sid, _) in &sessions {
+      self.revoke_family(user_id, sid, ttl).await?;
+    }
+    Ok(sessions.len())
+  }
+
+  /**
+   * drops a session's refresh token (the whole rotation family, since a session is one family)
+   * and remembers the revocation for `ttl` seconds, as long as any token of the family could still verify
+   */
+  async fn revoke_family(&self, user_id: &str, sid: &str, ttl: u64) -> Result<(), AppError> {
+    // marked first, a refresh that no longer finds the session must see why
+    self.set(None, &revoked_family_key(user_id, sid), "1", Some(ttl)).await?;
+    self.delete_session(user_id, sid).await
+  }
+
+  async fn is_family_revoked(&self, user_id: &str, sid: &str) -> Result<bool, AppError> {
+    Ok(self.get(&revoked_family_key(user_id, sid)).await?.is_some())
+  }
+
+  /**
+   * adds an access token to the denylist for the rest of its lifetime
+   */
+  async fn deny_access_token(&self, jti: &str, ttl: u64) -> Result<(), AppError> {
+    self.set(None, &denied_access_token_key(jti), "1", Some(ttl)).await
+  }
+
+  /**
+   * invalidates every access token of the user issued before `timestamp` that belongs to no session,
+   * `ttl` is the longest access token lifetime since older tokens have all expired by then
+   */
+  async fn revoke_access_tokens_before(&self, user_id: &str, timestamp: u64, ttl: u64) -> Result<(), AppError> {
+    self.set(None, &revoked_before_key(user_id), &timestamp.to_string(), Some(ttl)).await
+  }
+
+  /**
+   * returns whether the token is denied, either on its own or because its session was revoked,
+   * and the user's revocation watermark
+   */
+  async fn access_token_status(&self, user_id: &str, sid: Option<&str>, jti: &str) -> Result<(bool, Option<u64>), AppError> {
+    let revoked_before = self.get(&revoked_before_key(user_id)).await?
+    .and_then(|v| v.parse().ok());
+
+    let denied = self.get(&denied_access_token_key(jti)).await?.is_some() || match sid {
+      Some(sid) => self.is_family_revoked(user_id, sid).await?,
+      None => false
+    };
+
+    Ok((denied, revoked_before))
+  }
+}
+
+/**
+ * every key lives under `rt:` (refresh token families) or `at:` (access token revocations),
+ * so the store can share a redis instance with other apps.
+ * Each kind of key has its own fixed prefix, a session id can never make one key alias another
+ */
+fn session_key(user_id: &str, sid: &str) -> String {
+  format!("rt:sess:{}:{}", user_id, sid)
+}
+
+fn revoked_family_key(user_id: &str, sid: &str) -> String {
+  format!("rt:revoked:{}:{}", user_id, sid)
+}
+
+fn denied_access_token_key(jti: &str) -> String {
+  format!("at:denied:{}", jti)
+}
+
+fn revoked_before_key(user_id: &str) -> String {
+  format!("at:revoked_before:{}", user_id)
+}
+
+/**
+ * connects the backend selected by `token_store`
+ */
+pub async fn connect_from_config(logger: slog::Logger, config: &AppConfig) -> Result<RefreshTokenStore, AppError> {
+  let rts: RefreshTokenStore = match config.token_store {
+    TokenStoreBackend::Redis => Arc::new(RefreshTokenRedis::connect_from_config(logger, config).await?),
+    TokenStoreBackend::Memory => Arc::new(MemoryTokenStore::from_config(logger, config))
+  };
+  Ok(rts)
+}
+
+/**
+ * refreshes all tokens, returns the new access token and refresh token.
+ * Presenting a refresh token that was already rotated out revokes its whole family (OAuth 2.0 Security BCP),
+ * since either the client or an attacker is replaying a stolen token and we cannot tell which.
+ */
+pub async fn refresh_tokens(logger: slog::Logger, keys: &KeyRingRef, rts: &RefreshTokenStore, lifetimes: &LifetimePolicy, db: &DbRef, old_reftok: String, client: ClientInfo) -> Result<TokenPair, AppError> {
+  let session = JWTClaim::verify_refresh_token(keys, lifetimes, old_reftok.clone())?;
+  let (user_id, sid) = (session.user_id.to_string(), session.sid.clone());
+
+  // the raw value is kept to rotate only if nobody else rotated it meanwhile
+  let raw = match rts.get(&session_key(&user_id, &sid)).await? {
+    Some(v) => v,
+    None if rts.is_family_revoked(&user_id, &sid).await? => {
+      warn!(logger, "[security] refresh token of revoked family presented again for user {} (session {})", user_id, sid);
+      return Err(AppError::auth_error(401, "REVOKED_REFRESH_TOKEN", Some("this session has been revoked, please login again")));
+    },
+    None => return Err(AppError::auth_error(401, "NO_REFRESH_TOKEN", Some("you have an invalid refresh token")))
+  };
+
+  let curr = StoredSession::parse(raw.clone());
+  if curr.token != old_reftok {
+    return Err(reused_refresh_token(&logger, rts, lifetimes, &user_id, &sid).await);
+  }
+
+  // the identity is reloaded so username and roles changes reach the next access token
+  let user = fetch_by_id(db, session.user_id).await?;
+  let identity = fetch_identity(db, &user).await?;
+
+  match JWTClaim::issue(keys, rts, lifetimes, &identity, &session, curr.info.refreshed(client), Some(&raw)).await {
+    // a concurrent refresh with the same token rotated the session first
+    Err(err) if err.error_code == "REUSED_REFRESH_TOKEN" => Err(reused_refresh_token(&logger, rts, lifetimes, &user_id, &sid).await),
+    res => res
+  }
+}
+
+/**
+ * ends every session of a user at logoutAll and revokes every access token issued so far:
+ * those of listed sessions through their revoked family, the rest through the user's watermark.
+ * Returns how many sessions were ended
+ */
+pub async fn end_all_sessions(rts: &RefreshTokenStore, lifetimes: &LifetimePolicy, user_id: &str) -> Result<usize, AppError> {
+  let revoked = rts.revoke_user_sessions(user_id, lifetimes.max_refresh().max(lifetimes.max_access())).await?;
+  rts.revoke_access_tokens_before(user_id, jsonwebtoken::get_current_timestamp(), lifetimes.max_access()).await?;
+  Ok(revoked)
+}
+
+/**
+ * ends the session of `reftok` at logout: its family is revoked, so its access tokens stop working too.
+ * A token already rotated out is handled as reuse, unknown or expired tokens have nothing left to end
+ */
+pub async fn end_session(logger: slog::Logger, keys: &KeyRingRef, rts: &RefreshTokenStore, lifetimes: &LifetimePolicy, reftok: String) -> Result<(), AppError> {
+  let session = match JWTClaim::verify_refresh_token(keys, lifetimes, reftok.clone()) {
+    Ok(v) => v,
+    Err(_) => return Ok(())
+  };
+  let (user_id, sid) = (session.user_id.to_string(), session.sid);
+
+  match rts.get_session(&user_id, &sid).await? {
+    Some(curr) if curr.token != reftok => Err(reused_refresh_token(&logger, rts, lifetimes, &user_id, &sid).await),
+    Some(_) => rts.revoke_family(&user_id, &sid, lifetimes.max_refresh().max(lifetimes.max_access())).await,
+    None => Ok(())
+  }
+}
+
+/**
+ * revokes the family of a reused refresh token, returns the error to answer with
+ */
+async fn reused_refresh_token(logger: &slog::Logger, rts: &RefreshTokenStore, lifetimes: &LifetimePolicy, user_id: &str, sid: &str) -> AppError {
+  if let Err(err) = rts.revoke_family(user_id, sid, lifetimes.max_refresh().max(lifetimes.max_access())).await {
+    return err;
+  }
+  crit!(logger, "[security] rotated refresh token reused for user {} (session {}), revoked the token family", user_id, sid);
+  AppError::auth_error(401, "REUSED_REFRESH_TOKEN", Some("your refresh token has already been used, the session was revoked, please login again"))
+}
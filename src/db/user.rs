@@ -1,15 +1,24 @@
 use crate::{
-  models::user::User,
+  models::{user::User, session::{ClientInfo, SessionInfo}},
   errors::{AppError, TAppError},
-  auth::tokens::JWTClaim,
+  auth::{tokens::{JWTClaim, Identity, SessionOptions, TokenDelivery, TokenPair}, keys::KeyRingRef, lifetimes::LifetimePolicy, passwords::{PasswordCheck, PasswordHashing}, password_policy::PasswordPolicy},
 };
 
-use super::{Db, DbRef, redis::RefreshTokenStore};
+use super::{Db, DbRef, token_store::RefreshTokenStore};
 
 #[derive(Deserialize)]
 pub struct UserCredentials {
   pub email: String,
   pub password: String,
+  /**
+   * shown in the session list, e.g. "Work laptop"
+   */
+  #[serde(default)]
+  pub device_name: Option<String>,
+  #[serde(default)]
+  pub token_delivery: TokenDelivery,
+  #[serde(flatten)]
+  pub session: SessionOptions,
 }
 
 #[derive(Deserialize)]
@@ -17,55 +26,94 @@ pub struct UserCreate {
   pub username: String,
   pub email: String,
   pub password: String,
+  /**
+   * shown in the session list, e.g. "Work laptop"
+   */
+  #[serde(default)]
+  pub device_name: Option<String>,
+  #[serde(default)]
+  pub token_delivery: TokenDelivery,
+  #[serde(flatten)]
+  pub session: SessionOptions,
 }
 
-pub async fn fetch_from_token(token: String, db: &DbRef) -> Result<User, AppError> {
-  let dbwrite = Db::get_writable(db)?;
-  let user_id = JWTClaim::verify_access_token(token)?;
+pub async fn fetch_by_id(db: &DbRef, user_id: uuid::Uuid) -> Result<User, AppError> {
+  let (pool, logger) = Db::get_handles(db)?;
   let user = sqlx::query_as!(User, "SELECT * FROM users WHERE id = $1", user_id)
-  .fetch_one(&dbwrite.pool)
+  .fetch_one(&pool)
   .await
   .map_err(|err| {
     match err {
       sqlx::Error::RowNotFound => AppError::db_error(401, "USER_NOT_FOUND", Some("user not found with the given credentials")),
-      _ => AppError::crit_error(dbwrite.logger.clone(), "UNKNOWN_USER_ERROR", Some("user cannot be fetched, please try again later."))
+      _ => AppError::crit_error(logger.clone(), "UNKNOWN_USER_ERROR", Some("user cannot be fetched, please try again later."))
     }
   })?;
 
   Ok(user)
 }
 
-pub async fn user_login(rts: &RefreshTokenStore, db: &DbRef, creds: &UserCredentials) -> Result<(User, (String, String)), AppError> {
-  let dbwrite = Db::get_writable(db)?;
+/**
+ * the identity embedded in the user's access tokens, with the user's current roles
+ */
+pub async fn fetch_identity(db: &DbRef, user: &User) -> Result<Identity, AppError> {
+  let (pool, logger) = Db::get_handles(db)?;
+  let roles = sqlx::query_scalar!("SELECT roles.name FROM roles JOIN user_roles ON user_roles.role_id = roles.id WHERE user_roles.user_id = $1 ORDER BY roles.name", user.id)
+  .fetch_all(&pool)
+  .await
+  .map_err(|err| AppError::crit_error(logger.clone(), "UNKNOWN_ROLES_ERROR", Some("roles cannot be fetched, please try again later.")))?;
+
+  Ok(Identity {
+    user_id: user.id,
+    username: user.username.clone(),
+    roles
+  })
+}
+
+/**
+ * checks the credentials, a right password under an outdated hash is rehashed with the current parameters
+ */
+pub async fn user_login(keys: &KeyRingRef, rts: &RefreshTokenStore, lifetimes: &LifetimePolicy, passwords: &PasswordHashing, db: &DbRef, creds: &UserCredentials, client: ClientInfo) -> Result<(User, TokenPair), AppError> {
+  let (pool, logger) = Db::get_handles(db)?;
   
-  let user = sqlx::query_as!(User, "SELECT * FROM users WHERE email = $1 AND password = crypt($2, password)", creds.email, creds.password)
-  .fetch_one(&dbwrite.pool)
+  let user = sqlx::query_as!(User, "SELECT * FROM users WHERE email = $1", creds.email)
+  .fetch_optional(&pool)
   .await
-  .map_err(|err| {
-    match err {
-      sqlx::Error::RowNotFound => AppError::db_error(401, "USER_NOT_FOUND", Some("user not found with the given credentials")),
-      _ => AppError::crit_error(dbwrite.logger.clone(), "UNKNOWN_USER_ERROR", Some("user cannot be fetched, please try again later."))
-    }
-  })?;
+  .map_err(|err| AppError::crit_error(logger.clone(), "UNKNOWN_USER_ERROR", Some("user cannot be fetched, please try again later.")))?;
+
+  // unknown emails and wrong passwords are the same error, and take the same time
+  let user = match (passwords.verify(&creds.password, user.as_ref().map(|u| u.password.as_str())).await?, user) {
+    (PasswordCheck::Match, Some(user)) => user,
+    (PasswordCheck::MatchOutdated, Some(mut user)) => {
+      let hash = passwords.hash(&creds.password).await?;
+      // the login goes on with the old hash, the next one retries
+      match sqlx::query!("UPDATE users SET password = $1 WHERE id = $2", hash, user.id).execute(&pool).await {
+        Ok(_) => user.password = hash,
+        Err(err) => warn!(logger, "cannot rehash the password of user {}: {}", user.id, err)
+      };
+      user
+    },
+    _ => return Err(AppError::db_error(401, "USER_NOT_FOUND", Some("user not found with the given credentials")))
+  };
 
-  let access_token = JWTClaim::access_token(user.id.to_string())?;
-  let refresh_token = JWTClaim::refresh_token(dbwrite.logger.clone(), rts, user.id.to_string())?;
-  Ok((user, (access_token, refresh_token)))
+  let tokens = JWTClaim::new_session(keys, rts, lifetimes, &fetch_identity(db, &user).await?, creds.session.clone(), SessionInfo::new(client, creds.device_name.as_deref())).await?;
+  Ok((user, tokens))
 }
 
-pub async fn user_create(rts: &RefreshTokenStore, db: &DbRef, new_user: &UserCreate) -> Result<(User, (String, String)), AppError> {
-  let dbwrite = Db::get_writable(db)?;
-  let user = sqlx::query_as!(User, "INSERT INTO users (username, email, password) VALUES ($1, $2, crypt($3, gen_salt('bf'))) RETURNING *", new_user.username, new_user.email, new_user.password)
-  .fetch_one(&dbwrite.pool)
+#[allow(clippy::too_many_arguments)]
+pub async fn user_create(keys: &KeyRingRef, rts: &RefreshTokenStore, lifetimes: &LifetimePolicy, passwords: &PasswordHashing, policy: &PasswordPolicy, db: &DbRef, new_user: &UserCreate, client: ClientInfo) -> Result<(User, TokenPair), AppError> {
+  policy.check(&new_user.password, &new_user.username, &new_user.email).await?;
+  let (pool, logger) = Db::get_handles(db)?;
+  let hash = passwords.hash(&new_user.password).await?;
+  let user = sqlx::query_as!(User, "INSERT INTO users (username, email, password) VALUES ($1, $2, $3) RETURNING *", new_user.username, new_user.email, hash)
+  .fetch_one(&pool)
   .await
   .map_err(|err| {
     match err {
       sqlx::Error::Database(dberr) => AppError::db_error(400, "ERR_FROM_DB", Some(dberr.to_string())),
-      _ => AppError::crit_error(dbwrite.logger.clone(), "UNKNOWN_USER_ERROR", Some(err.to_string()))
+      _ => AppError::crit_error(logger.clone(), "UNKNOWN_USER_ERROR", Some(err.to_string()))
     }
   })?;
 
-  let access_token = JWTClaim::access_token(user.id.to_string())?;
-  let refresh_token = JWTClaim::refresh_token(dbwrite.logger.clone(), rts, user.id.to_string())?;
-  Ok((user, (access_token, refresh_token)))
+  let tokens = JWTClaim::new_session(keys, rts, lifetimes, &fetch_identity(db, &user).await?, new_user.session.clone(), SessionInfo::new(client, new_user.device_name.as_deref())).await?;
+  Ok((user, tokens))
 }
\ No newline at end of file
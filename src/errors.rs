@@ -1,16 +1,18 @@
 use std::fmt::Debug;
 
-use actix_web::{ResponseError, HttpResponseBuilder, http::{header::ContentType, StatusCode}};
+use actix_web::{ResponseError, HttpResponseBuilder, http::{header::{ContentType, WWW_AUTHENTICATE}, StatusCode}};
 
 use crate::resp::GenericResponse;
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Serialize, Clone)]
 pub enum AppErrorType {
   DbError,
   HostError,
   CritError,
   AuthError,
-  UserError
+  UserError,
+  ConfigError
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -19,7 +21,29 @@ pub struct AppError {
   pub status_code: u16,
   pub error_code: String,
   pub message: Option<String>,
-  pub error_type: AppErrorType
+  pub error_type: AppErrorType,
+  /**
+   * sent as the `WWW-Authenticate` header
+   */
+  #[serde(skip_serializing)]
+  pub challenge: Option<String>,
+  /**
+   * machine readable specifics, e.g. the password rules that failed
+   */
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub details: Option<serde_json::Value>
+}
+
+impl AppError {
+  pub fn with_challenge<T: ToString>(mut self, challenge: T) -> Self {
+    self.challenge = Some(challenge.to_string());
+    self
+  }
+
+  pub fn with_details<T: serde::Serialize>(mut self, details: T) -> Self {
+    self.details = serde_json::to_value(details).ok();
+    self
+  }
 }
 
 impl std::fmt::Display for AppError {
@@ -30,7 +54,11 @@ impl std::fmt::Display for AppError {
 
 impl ResponseError for AppError {
   fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
-    HttpResponseBuilder::new(StatusCode::from_u16(self.status_code).unwrap())
+    let mut res = HttpResponseBuilder::new(StatusCode::from_u16(self.status_code).unwrap());
+    if let Some(challenge) = &self.challenge {
+      res.insert_header((WWW_AUTHENTICATE, challenge.as_str()));
+    }
+    res
     .insert_header(ContentType::json())
     .json(GenericResponse::err_res(self.status_code, self.clone())) 
   }
@@ -42,7 +70,9 @@ impl From<std::io::Error> for AppError {
       status_code: 500,
       error_code: "CANNOT_BIND_TO_HOST".into(),
       message: Some(value.to_string()),
-      error_type: AppErrorType::HostError
+      error_type: AppErrorType::HostError,
+      challenge: None,
+      details: None
     }
   }
 }
@@ -52,24 +82,29 @@ pub trait TAppError {
   fn user_error<T: ToString, R: ToString>(status_code: u16, error_code: T, message: Option<R>) -> Self;
   fn crit_error<T: ToString + Debug, R: ToString + Debug>(logger: slog::Logger, error_code: T, message: Option<R>) -> Self;
   fn auth_error<T: ToString, R: ToString>(status_code: u16, error_code: T, message: Option<R>) -> Self;
+  fn config_error<T: ToString, R: ToString>(error_code: T, message: Option<R>) -> Self;
 }
 
 impl TAppError for AppError {
   fn db_error<T: ToString, R: ToString>(status_code: u16, error_code: T, message: Option<R>) -> Self {
     Self {
-      status_code: status_code,
+      status_code,
       error_code: error_code.to_string(),
       message: message.map(|s| s.to_string()),
-      error_type: AppErrorType::DbError
+      error_type: AppErrorType::DbError,
+      challenge: None,
+      details: None
     }
   }
 
   fn user_error<T: ToString, R: ToString>(status_code: u16, error_code: T, message: Option<R>) -> Self {
     Self {
-      status_code: status_code,
+      status_code,
       error_code: error_code.to_string(),
       message: message.map(|s| s.to_string()),
-      error_type: AppErrorType::UserError
+      error_type: AppErrorType::UserError,
+      challenge: None,
+      details: None
     }
   }
 
@@ -79,6 +114,19 @@ impl TAppError for AppError {
       error_code: error_code.to_string(),
       message: message.map(|s| s.to_string()),
       error_type: AppErrorType::AuthError,
+      challenge: None,
+      details: None
+    }
+  }
+
+  fn config_error<T: ToString, R: ToString>(error_code: T, message: Option<R>) -> Self {
+    Self {
+      status_code: 500,
+      error_code: error_code.to_string(),
+      message: message.map(|s| s.to_string()),
+      error_type: AppErrorType::ConfigError,
+      challenge: None,
+      details: None
     }
   }
 
@@ -88,7 +136,9 @@ impl TAppError for AppError {
       status_code: 500,
       error_code: error_code.to_string(),
       message: message.map(|s| s.to_string()),
-      error_type: AppErrorType::CritError
+      error_type: AppErrorType::CritError,
+      challenge: None,
+      details: None
     }
   }
 }
\ No newline at end of file
@@ -18,23 +18,24 @@ extern crate serde;
 #[macro_use]
 extern crate actix_web;
 
-#[macro_use]
-extern crate dotenv_codegen;
-
 #[macro_use]
 extern crate slog;
 extern crate slog_term;
 extern crate slog_async;
 
-use db::{DbRef, redis::RefreshTokenStore};
+use auth::keys::{KeyRing, KeyRingRef};
+use config::{AppConfig, FromConfig};
+use db::{DbRef, token_store::RefreshTokenStore};
 use errors::AppError;
 use std::sync::{RwLock, Arc};
 
 #[tokio::main]
 async fn main() -> Result<(), AppError> {
   let logger = utils::logger::configure_log();
-  let db: DbRef = Arc::new(RwLock::new(db::Db::connect_from_env(logger.clone()).await?));
-  let refresh_token_store: RefreshTokenStore = Arc::new(RwLock::new(db::redis::RefreshTokenRedis::init_from_env(logger.clone())?));
+  let config = AppConfig::load()?;
+  let db: DbRef = Arc::new(RwLock::new(db::Db::connect_from_config(logger.clone(), &config).await?));
+  let refresh_token_store: RefreshTokenStore = db::token_store::connect_from_config(logger.clone(), &config).await?;
+  let keys: KeyRingRef = Arc::new(RwLock::new(KeyRing::from_config(logger.clone(), &config)?));
 
-  server::Server::start_from_env(db.clone(), logger.clone(), refresh_token_store.clone()).await
+  server::Server::start_from_config(db.clone(), logger.clone(), &config, refresh_token_store.clone(), keys.clone()).await
 }
\ No newline at end of file
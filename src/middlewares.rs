@@ -1 +1,6 @@
-pub mod auth;
\ No newline at end of file
+pub mod auth;
+pub mod admin;
+pub mod role;
+pub mod scope;
+pub mod origin;
+pub mod cors;
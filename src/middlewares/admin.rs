@@ -0,0 +1,25 @@
+use actix_web::HttpRequest;
+
+use crate::{errors::{AppError, TAppError}, config::AppConfig};
+
+pub fn with_admin_key(req: &HttpRequest, config: &AppConfig) -> Result<(), AppError> {
+  let expected = match &config.admin_api_key {
+    Some(v) => v,
+    None => return Err(AppError::auth_error(403, "ADMIN_DISABLED", Some("admin routes are disabled, set ADMIN_API_KEY to enable them")))
+  };
+
+  let given = match req.headers().get("X-Admin-Key") {
+    Some(v) => v.as_bytes(),
+    None => return Err(AppError::auth_error(401, "NO_ADMIN_KEY", Some("need the admin key in the X-Admin-Key header")))
+  };
+
+  // constant time comparison, the length of the key is not a secret
+  let matches = given.len() == expected.len()
+  && given.iter().zip(expected.as_bytes()).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0;
+
+  if !matches {
+    return Err(AppError::auth_error(401, "INVALID_ADMIN_KEY", Some("invalid admin key")));
+  }
+
+  Ok(())
+}
@@ -1,20 +1,158 @@
-use actix_web::HttpRequest;
+use std::{future::{ready, Ready}, ops::Deref, rc::Rc, sync::Arc};
 
-use crate::{errors::{AppError, TAppError}, models::user::User, db::{user::fetch_from_token, DbRef}};
+use actix_web::{
+  HttpRequest, HttpMessage, FromRequest, web,
+  dev::{Payload, Service, ServiceRequest, ServiceResponse, Transform, forward_ready}
+};
+use futures_util::future::LocalBoxFuture;
+use tokio::sync::OnceCell;
 
-pub async fn with_auth(req: &HttpRequest, db: &DbRef) -> Result<User, AppError> {
+use crate::{errors::{AppError, TAppError}, models::user::User, db::{user::fetch_by_id, DbRef, token_store::RefreshTokenStore}, auth::{keys::KeyRingRef, tokens::{JWTClaim, Identity}, scopes}};
+
+/**
+ * verifies the bearer token, the identity, granted scopes and session id come from its claims without touching the database.
+ * Failures carry the RFC 6750 `WWW-Authenticate` challenge
+ */
+pub async fn with_auth(req: &HttpRequest, keys: &KeyRingRef, rts: &RefreshTokenStore) -> Result<(Identity, Vec<String>, Option<String>), AppError> {
+  let access_token = bearer_token(req)
+  .map_err(|err| err.with_challenge("Bearer"))?;
+  JWTClaim::verify_access_token(keys, rts, access_token.to_string()).await
+  .map_err(|err| match err.status_code {
+    401 => err.with_challenge("Bearer error=\"invalid_token\""),
+    _ => err
+  })
+}
+
+pub fn bearer_token(req: &HttpRequest) -> Result<&str, AppError> {
   // Bearer {token}
   let hdr = match req.headers().get("Authorization") {
     Some(v) => v.to_str().map_err(|err| AppError::auth_error(401, "INVALID_AUTH_HEADER", Some("auth header must be of type utf-8")))?,
     None => return Err(AppError::auth_error(401, "PROTECTED_ROUTE", Some("need credentials in Authorization header")))
   };
 
-  let access_token = match hdr.trim().split(' ').last() {
-    Some(v) => v,
-    None => return Err(AppError::auth_error(401, "NO_ACCESS_TOKEN", Some("access token must exist in Authorization header, please note that the parser reads the last substring as the token")))
-  };
+  match hdr.trim().split(' ').next_back() {
+    Some(v) => Ok(v),
+    None => Err(AppError::auth_error(401, "NO_ACCESS_TOKEN", Some("access token must exist in Authorization header, please note that the parser reads the last substring as the token")))
+  }
+}
+
+/**
+ * The identity behind the bearer token, taking it as a handler argument makes the route protected.
+ * Use `Option<AuthenticatedUser>` where login is optional, missing or invalid credentials then give `None`.
+ * The `User` row is only loaded when a handler calls `user`.
+ */
+#[derive(Clone)]
+pub struct AuthenticatedUser {
+  pub identity: Identity,
+  pub scope: Vec<String>,
+  /**
+   * session the access token was issued to
+   */
+  pub sid: Option<String>,
+  user: Arc<OnceCell<User>>
+}
+
+impl AuthenticatedUser {
+  /**
+   * reuses the identity stored by `RequireAuth`, otherwise verifies the request itself
+   */
+  pub async fn from_http_request(req: HttpRequest) -> Result<Self, AppError> {
+    if let Some(user) = req.extensions().get::<AuthenticatedUser>() {
+      return Ok(user.clone());
+    }
+
+    let (keys, rts) = match (
+      req.app_data::<web::Data<KeyRingRef>>(),
+      req.app_data::<web::Data<RefreshTokenStore>>()
+    ) {
+      (Some(keys), Some(rts)) => (keys.clone(), rts.clone()),
+      _ => return Err(AppError::auth_error(500, "AUTH_NOT_CONFIGURED", Some("auth app data is not registered")))
+    };
+
+    let (identity, scope, sid) = with_auth(&req, &keys, &rts).await?;
+    Ok(Self {
+      identity,
+      scope,
+      sid,
+      user: Arc::new(OnceCell::new())
+    })
+  }
+
+  /**
+   * 403 `INSUFFICIENT_SCOPE` unless the token was granted `scope`
+   */
+  pub fn require_scope(&self, scope: &str) -> Result<(), AppError> {
+    scopes::require(&self.scope, scope)
+  }
+
+  /**
+   * loads the user row on first use, later calls return the same row
+   */
+  pub async fn user(&self, db: &DbRef) -> Result<&User, AppError> {
+    self.user.get_or_try_init(|| fetch_by_id(db, self.identity.user_id)).await
+  }
+}
+
+impl Deref for AuthenticatedUser {
+  type Target = Identity;
+  fn deref(&self) -> &Self::Target {
+    &self.identity
+  }
+}
+
+impl FromRequest for AuthenticatedUser {
+  type Error = AppError;
+  type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+  fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+    Box::pin(Self::from_http_request(req.clone()))
+  }
+}
+
+/**
+ * Rejects unauthenticated requests before they reach any route of the wrapped scope or resource,
+ * e.g. `web::scope("/account").wrap(RequireAuth)`. Handlers behind it still take `AuthenticatedUser`,
+ * which then comes from the request extensions without verifying the token twice.
+ */
+pub struct RequireAuth;
+
+impl<S, B> Transform<S, ServiceRequest> for RequireAuth
+where
+  S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+  B: 'static
+{
+  type Response = ServiceResponse<B>;
+  type Error = actix_web::Error;
+  type Transform = RequireAuthMiddleware<S>;
+  type InitError = ();
+  type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+  fn new_transform(&self, service: S) -> Self::Future {
+    ready(Ok(RequireAuthMiddleware { service: Rc::new(service) }))
+  }
+}
+
+pub struct RequireAuthMiddleware<S> {
+  service: Rc<S>
+}
+
+impl<S, B> Service<ServiceRequest> for RequireAuthMiddleware<S>
+where
+  S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+  B: 'static
+{
+  type Response = ServiceResponse<B>;
+  type Error = actix_web::Error;
+  type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
 
-  let user = fetch_from_token(access_token.to_string(), db).await?;
+  forward_ready!(service);
 
-  Ok(user)
-}
\ No newline at end of file
+  fn call(&self, req: ServiceRequest) -> Self::Future {
+    let service = self.service.clone();
+    Box::pin(async move {
+      let user = AuthenticatedUser::from_http_request(req.request().clone()).await?;
+      req.extensions_mut().insert(user);
+      service.call(req).await
+    })
+  }
+}
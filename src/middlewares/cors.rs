@@ -0,0 +1,25 @@
+use actix_cors::Cors;
+use actix_web::http::header;
+
+use crate::{config::CorsConfig, auth::origins::OriginPolicyRef};
+
+/**
+ * the CORS middleware of one group of routes, every worker builds its own
+ */
+pub fn cors(config: &CorsConfig, origins: OriginPolicyRef) -> Cors {
+  let mut cors = Cors::default()
+  .allowed_origin_fn(move |origin, head| {
+    let host = head.headers().get(header::HOST).and_then(|v| v.to_str().ok());
+    origin.to_str().map(|origin| origins.is_allowed_cors(origin, host)).unwrap_or(false)
+  })
+  .allowed_methods(config.allowed_methods.iter().map(String::as_str))
+  .max_age(config.max_age);
+
+  if !config.allowed_headers.is_empty() {
+    cors = cors.allowed_headers(config.allowed_headers.iter().map(String::as_str));
+  }
+  if config.supports_credentials {
+    cors = cors.supports_credentials();
+  }
+  cors
+}
@@ -0,0 +1,62 @@
+use std::future::{ready, Ready};
+
+use actix_web::{
+  web,
+  dev::{Service, ServiceRequest, ServiceResponse, Transform, forward_ready}
+};
+use futures_util::future::LocalBoxFuture;
+
+use crate::{errors::{AppError, TAppError}, auth::origins::OriginPolicyRef};
+
+/**
+ * CSRF protection for routes authenticated by a cookie, e.g. `#[post("/refreshAccessToken", wrap = "RequireTrustedOrigin")]`.
+ * Requests must come from the server's own origin or one of `allowed_origins`, see `OriginPolicy::check_request`.
+ */
+pub struct RequireTrustedOrigin;
+
+impl<S, B> Transform<S, ServiceRequest> for RequireTrustedOrigin
+where
+  S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+  B: 'static
+{
+  type Response = ServiceResponse<B>;
+  type Error = actix_web::Error;
+  type Transform = RequireTrustedOriginMiddleware<S>;
+  type InitError = ();
+  type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+  fn new_transform(&self, service: S) -> Self::Future {
+    ready(Ok(RequireTrustedOriginMiddleware {
+      service
+    }))
+  }
+}
+
+pub struct RequireTrustedOriginMiddleware<S> {
+  service: S
+}
+
+impl<S, B> Service<ServiceRequest> for RequireTrustedOriginMiddleware<S>
+where
+  S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+  B: 'static
+{
+  type Response = ServiceResponse<B>;
+  type Error = actix_web::Error;
+  type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+  forward_ready!(service);
+
+  fn call(&self, req: ServiceRequest) -> Self::Future {
+    let checked = match req.app_data::<web::Data<OriginPolicyRef>>() {
+      Some(origins) => origins.check_request(req.request()),
+      None => Err(AppError::auth_error(500, "ORIGINS_NOT_CONFIGURED", Some("origin policy app data is not registered")))
+    };
+    if let Err(err) = checked {
+      return Box::pin(async move { Err(err.into()) });
+    }
+
+    let fut = self.service.call(req);
+    Box::pin(fut)
+  }
+}
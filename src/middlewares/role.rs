@@ -0,0 +1,72 @@
+use std::{future::{ready, Ready}, rc::Rc};
+
+use actix_web::{
+  HttpMessage,
+  dev::{Service, ServiceRequest, ServiceResponse, Transform, forward_ready}
+};
+use futures_util::future::LocalBoxFuture;
+
+use super::auth::AuthenticatedUser;
+
+/**
+ * Only lets users with the role through, e.g. `web::scope("/staff").wrap(RequireRole::new("admin"))`.
+ * Unauthenticated requests get the usual 401, authenticated ones without the role a 403 `MISSING_ROLE`.
+ */
+pub struct RequireRole {
+  role: Rc<str>
+}
+
+impl RequireRole {
+  pub fn new(role: &str) -> Self {
+    Self {
+      role: role.into()
+    }
+  }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequireRole
+where
+  S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+  B: 'static
+{
+  type Response = ServiceResponse<B>;
+  type Error = actix_web::Error;
+  type Transform = RequireRoleMiddleware<S>;
+  type InitError = ();
+  type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+  fn new_transform(&self, service: S) -> Self::Future {
+    ready(Ok(RequireRoleMiddleware {
+      service: Rc::new(service),
+      role: self.role.clone()
+    }))
+  }
+}
+
+pub struct RequireRoleMiddleware<S> {
+  service: Rc<S>,
+  role: Rc<str>
+}
+
+impl<S, B> Service<ServiceRequest> for RequireRoleMiddleware<S>
+where
+  S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+  B: 'static
+{
+  type Response = ServiceResponse<B>;
+  type Error = actix_web::Error;
+  type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+  forward_ready!(service);
+
+  fn call(&self, req: ServiceRequest) -> Self::Future {
+    let service = self.service.clone();
+    let role = self.role.clone();
+    Box::pin(async move {
+      let user = AuthenticatedUser::from_http_request(req.request().clone()).await?;
+      user.require_role(&role)?;
+      req.extensions_mut().insert(user);
+      service.call(req).await
+    })
+  }
+}
@@ -0,0 +1,72 @@
+use std::{future::{ready, Ready}, rc::Rc};
+
+use actix_web::{
+  HttpMessage,
+  dev::{Service, ServiceRequest, ServiceResponse, Transform, forward_ready}
+};
+use futures_util::future::LocalBoxFuture;
+
+use super::auth::AuthenticatedUser;
+
+/**
+ * Only lets tokens granted the scope through, e.g. `#[post("/logoutAll", wrap = "RequireScope::new(\"write\")")]`.
+ * Unauthenticated requests get the usual 401, tokens without the scope a 403 `INSUFFICIENT_SCOPE`.
+ */
+pub struct RequireScope {
+  scope: Rc<str>
+}
+
+impl RequireScope {
+  pub fn new(scope: &str) -> Self {
+    Self {
+      scope: scope.into()
+    }
+  }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequireScope
+where
+  S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+  B: 'static
+{
+  type Response = ServiceResponse<B>;
+  type Error = actix_web::Error;
+  type Transform = RequireScopeMiddleware<S>;
+  type InitError = ();
+  type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+  fn new_transform(&self, service: S) -> Self::Future {
+    ready(Ok(RequireScopeMiddleware {
+      service: Rc::new(service),
+      scope: self.scope.clone()
+    }))
+  }
+}
+
+pub struct RequireScopeMiddleware<S> {
+  service: Rc<S>,
+  scope: Rc<str>
+}
+
+impl<S, B> Service<ServiceRequest> for RequireScopeMiddleware<S>
+where
+  S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+  B: 'static
+{
+  type Response = ServiceResponse<B>;
+  type Error = actix_web::Error;
+  type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+  forward_ready!(service);
+
+  fn call(&self, req: ServiceRequest) -> Self::Future {
+    let service = self.service.clone();
+    let scope = self.scope.clone();
+    Box::pin(async move {
+      let user = AuthenticatedUser::from_http_request(req.request().clone()).await?;
+      user.require_scope(&scope)?;
+      req.extensions_mut().insert(user);
+      service.call(req).await
+    })
+  }
+}
@@ -0,0 +1,80 @@
+use std::net::SocketAddr;
+
+use actix_web::HttpRequest;
+
+const MAX_USER_AGENT_LEN: usize = 256;
+const MAX_DEVICE_NAME_LEN: usize = 64;
+
+/**
+ * where a request comes from, recorded at login and on every refresh
+ */
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+  pub ip: Option<String>,
+  pub user_agent: Option<String>
+}
+
+impl ClientInfo {
+  /**
+   * the ip honours `Forwarded` / `X-Forwarded-For`, it is informative only and must not be trusted for access control
+   */
+  pub fn from_request(req: &HttpRequest) -> Self {
+    let ip = req.connection_info().realip_remote_addr()
+    .map(|addr| match addr.parse::<SocketAddr>() {
+      Ok(v) => v.ip().to_string(),
+      Err(_) => addr.to_string()
+    });
+    let user_agent = req.headers().get("User-Agent")
+    .and_then(|v| v.to_str().ok())
+    .map(|v| truncate(v, MAX_USER_AGENT_LEN));
+
+    Self {
+      ip,
+      user_agent
+    }
+  }
+}
+
+/**
+ * what is stored about a session next to its refresh token, listed at `GET /auth/sessions`
+ */
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SessionInfo {
+  pub created_at: u64,
+  pub last_refreshed_at: u64,
+  pub ip: Option<String>,
+  pub user_agent: Option<String>,
+  /**
+   * name given by the client at login, e.g. "Work laptop"
+   */
+  pub device_name: Option<String>
+}
+
+impl SessionInfo {
+  pub fn new(client: ClientInfo, device_name: Option<&str>) -> Self {
+    let now = jsonwebtoken::get_current_timestamp();
+    Self {
+      created_at: now,
+      last_refreshed_at: now,
+      ip: client.ip,
+      user_agent: client.user_agent,
+      device_name: device_name.map(|v| truncate(v.trim(), MAX_DEVICE_NAME_LEN)).filter(|v| !v.is_empty())
+    }
+  }
+
+  /**
+   * the same session seen again from `client`
+   */
+  pub fn refreshed(self, client: ClientInfo) -> Self {
+    Self {
+      last_refreshed_at: jsonwebtoken::get_current_timestamp(),
+      ip: client.ip,
+      user_agent: client.user_agent,
+      ..self
+    }
+  }
+}
+
+fn truncate(v: &str, max_chars: usize) -> String {
+  v.chars().take(max_chars).collect()
+}
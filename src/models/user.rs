@@ -1,4 +1,4 @@
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 pub struct User {
   pub id: uuid::Uuid,
   pub username: String,
@@ -1,2 +1,4 @@
 pub mod home;
-pub mod auth;
\ No newline at end of file
+pub mod auth;
+pub mod jwks;
+pub mod admin;
\ No newline at end of file
@@ -0,0 +1,53 @@
+use actix_web::{Responder, web, HttpRequest};
+use serde_json::json;
+
+use crate::{
+  auth::keys::{KeyRing, KeyRingRef, KeySet},
+  config::ConfigRef,
+  errors::{AppError, TAppError},
+  middlewares::admin::with_admin_key,
+  utils::logger::LoggerRef
+};
+
+pub fn admin_scope() -> actix_web::Scope {
+  web::scope("/admin")
+  .service(list_keys)
+  .service(promote_key)
+}
+
+fn describe(keys: &KeyRing) -> serde_json::Value {
+  json!({
+    "access": keys.access.describe(),
+    "refresh": keys.refresh.describe()
+  })
+}
+
+#[get("/keys")]
+async fn list_keys(req: HttpRequest, config: web::Data<ConfigRef>, keys: web::Data<KeyRingRef>) -> Result<impl Responder, AppError> {
+  with_admin_key(&req, &config)?;
+  let keys = KeyRing::get_readable(&keys)?;
+  Ok(ok_res!(200, describe(&keys)))
+}
+
+/**
+ * makes a staged key the signing key of the `access` or `refresh` set,
+ * the previous signing key keeps verifying until the tokens it signed have expired
+ */
+#[post("/keys/{set}/{kid}/promote")]
+async fn promote_key(req: HttpRequest, path: web::Path<(String, String)>, config: web::Data<ConfigRef>, keys: web::Data<KeyRingRef>, logger: web::Data<LoggerRef>) -> Result<impl Responder, AppError> {
+  with_admin_key(&req, &config)?;
+  let (set, kid) = path.into_inner();
+
+  let mut keys = KeyRing::get_writable(&keys)?;
+  let key_set: &mut KeySet = match set.as_str() {
+    "access" => &mut keys.access,
+    "refresh" => &mut keys.refresh,
+    _ => return Err(AppError::user_error(404, "UNKNOWN_KEY_SET", Some("key set must be access or refresh")))
+  };
+  key_set.promote(&kid)?;
+
+  let logger = logger.read().unwrap();
+  warn!(logger, "promoted {} signing key to kid {}", set, kid);
+
+  Ok(ok_res!(200, describe(&keys)))
+}
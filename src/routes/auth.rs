@@ -1,12 +1,12 @@
 use actix_web::{
   Responder,
   web,
-  cookie::{Cookie, time::Duration, SameSite},
-  HttpRequest
+  HttpRequest,
+  HttpResponse
 };
 use serde_json::json;
 
-use crate::{db::{DbRef, self, user::{UserCredentials, UserCreate}, redis::{RefreshTokenStore, RefreshTokenRedis}}, errors::{AppError, TAppError}, models::user::User, middlewares::auth::with_auth, utils::logger::LoggerRef};
+use crate::{db::{DbRef, self, user::{UserCredentials, UserCreate}, token_store::{self, RefreshTokenStore}}, errors::{AppError, TAppError}, models::{user::User, session::{ClientInfo, SessionInfo}}, middlewares::{auth::{AuthenticatedUser, bearer_token}, scope::RequireScope, origin::RequireTrustedOrigin}, utils::logger::LoggerRef, auth::{keys::KeyRingRef, origins::OriginPolicyRef, lifetimes::LifetimePolicyRef, cookies::{CookiePolicy, CookiePolicyRef}, passwords::PasswordHashingRef, password_policy::PasswordPolicyRef, tokens::{JWTClaim, TokenDelivery, TokenPair}}};
 
 pub fn auth_scope() -> actix_web::Scope {
   web::scope("/auth")
@@ -14,80 +14,235 @@ pub fn auth_scope() -> actix_web::Scope {
   .service(register_user)
   .service(me)
   .service(refresh_access_token)
+  .service(token)
+  .service(logout)
+  .service(logout_all)
+  .service(list_sessions)
+  .service(delete_session)
 }
 
 #[derive(Serialize)]
 struct UserSigninRes {
   user: User,
-  access_token: String
+  access_token: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  refresh_token: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  refresh_token_expires_in: Option<u64>
 }
 
-#[get("/me")]
-async fn me(req: HttpRequest, db: web::Data<DbRef>) -> Result<impl Responder, AppError> {
-  let user = with_auth(&req, &db).await?;
+/**
+ * the refresh token goes in the refresh cookie or in the body, as the client asked
+ */
+fn signin_response(cookies: &CookiePolicy, user: User, tokens: TokenPair, delivery: TokenDelivery) -> HttpResponse {
+  match delivery {
+    TokenDelivery::Cookie => {
+      let rtcookie = cookies.refresh_cookie(&tokens);
+      ok_res!(200, UserSigninRes{
+        user,
+        access_token: tokens.access_token,
+        refresh_token: None,
+        refresh_token_expires_in: None
+      }, rtcookie)
+    },
+    TokenDelivery::Body => HttpResponse::Ok().json(ok_res_inner!(200, UserSigninRes{
+      user,
+      access_token: tokens.access_token,
+      refresh_token: Some(tokens.refresh_token),
+      refresh_token_expires_in: Some(tokens.refresh_token_ttl)
+    }))
+  }
+}
+
+#[get("/me", wrap = "RequireScope::new(\"read\")")]
+async fn me(user: AuthenticatedUser, db: web::Data<DbRef>) -> Result<impl Responder, AppError> {
+  let user = user.user(&db).await?.clone();
   Ok(ok_res!(200, user))
 }
 
 #[post("/login")]
-async fn login_user(rts: web::Data<RefreshTokenStore>, db: web::Data<DbRef>, body: web::Json<UserCredentials>) -> Result<impl Responder, AppError> {
-  let user = db::user::user_login(&rts, &db, &body.0)
+#[allow(clippy::too_many_arguments)]
+async fn login_user(req: HttpRequest, keys: web::Data<KeyRingRef>, rts: web::Data<RefreshTokenStore>, lifetimes: web::Data<LifetimePolicyRef>, cookies: web::Data<CookiePolicyRef>, passwords: web::Data<PasswordHashingRef>, db: web::Data<DbRef>, body: web::Json<UserCredentials>) -> Result<impl Responder, AppError> {
+  let (user, tokens) = db::user::user_login(&keys, &rts, &lifetimes, &passwords, &db, &body.0, ClientInfo::from_request(&req))
   .await?;
 
-  let rtcookie = Cookie::build("jwt", user.1.1)
-  .same_site(SameSite::None)
-  .http_only(true)
-  .secure(true)
-  .max_age(Duration::days(2))
-  .finish();
-
-  Ok(
-    ok_res!(200, UserSigninRes{
-      user: user.0,
-      access_token: user.1.0
-    }, rtcookie)
-  )
+  Ok(signin_response(&cookies, user, tokens, body.token_delivery))
 }
 
 #[post("/register")]
-async fn register_user(rts: web::Data<RefreshTokenStore>, db: web::Data<DbRef>, body: web::Json<UserCreate>) -> Result<impl Responder, AppError> {
-  let user = db::user::user_create(&rts, &db, &body.0)
+#[allow(clippy::too_many_arguments)]
+async fn register_user(req: HttpRequest, keys: web::Data<KeyRingRef>, rts: web::Data<RefreshTokenStore>, lifetimes: web::Data<LifetimePolicyRef>, cookies: web::Data<CookiePolicyRef>, passwords: web::Data<PasswordHashingRef>, policy: web::Data<PasswordPolicyRef>, db: web::Data<DbRef>, body: web::Json<UserCreate>) -> Result<impl Responder, AppError> {
+  let (user, tokens) = db::user::user_create(&keys, &rts, &lifetimes, &passwords, &policy, &db, &body.0, ClientInfo::from_request(&req))
   .await?;
 
-  let rtcookie = Cookie::build("jwt", user.1.1)
-  .same_site(SameSite::None)
-  .http_only(true)
-  .secure(true)
-  .max_age(Duration::days(2))
-  .finish();
-
-  Ok(
-    ok_res!(200, UserSigninRes{
-      user: user.0,
-      access_token: user.1.0
-    }, rtcookie)
-  )
+  Ok(signin_response(&cookies, user, tokens, body.token_delivery))
 }
 
-#[get("/refreshAccessToken")]
-async fn refresh_access_token(req: HttpRequest, rts: web::Data<RefreshTokenStore>, logger: web::Data<LoggerRef>) -> Result<impl Responder, AppError> {
-  let cookies = req.cookies()
-  .map_err(|e| AppError::auth_error(400, "CANNOT_PARSE_COOKIES", Some(e.to_string())))?;
-  let jwt_cookie = match cookies.iter().find(|cookie| cookie.name() == "jwt".to_string()) {
+/**
+ * rotates the refresh token in the refresh cookie, only from trusted origins since browsers attach the cookie to any request
+ */
+#[post("/refreshAccessToken", wrap = "RequireTrustedOrigin")]
+async fn refresh_access_token(req: HttpRequest, keys: web::Data<KeyRingRef>, rts: web::Data<RefreshTokenStore>, lifetimes: web::Data<LifetimePolicyRef>, cookies: web::Data<CookiePolicyRef>, db: web::Data<DbRef>, logger: web::Data<LoggerRef>) -> Result<impl Responder, AppError> {
+  let old_reftok = match cookies.read(&req)? {
     Some(v) => v,
-    None => return Err(AppError::auth_error(401, "NO_JWT_REFTOKEN_COOKIE", Some("no jwt cookie in header")))
+    None => return Err(AppError::auth_error(401, "NO_JWT_REFTOKEN_COOKIE", Some(format!("no {} cookie in header", cookies.name()))))
   };
 
-  let logger = logger.clone();
-  let logger = logger.write().unwrap();
-  
-  let tokens = RefreshTokenRedis::refresh_tokens(logger.clone(), &rts, jwt_cookie.value().to_string())?;
+  let logger = logger.write().unwrap().clone();
+
+  let tokens = token_store::refresh_tokens(logger, &keys, &rts, &lifetimes, &db, old_reftok, ClientInfo::from_request(&req)).await?;
 
-  let new_jwt_cookie = Cookie::build("jwt", tokens.1).finish();
+  let new_jwt_cookie = cookies.refresh_cookie(&tokens);
   Ok(ok_res!(
     200,
     json!({
-      "access_token": tokens.0
+      "access_token": tokens.access_token
     }),
     new_jwt_cookie
   ))
-}
\ No newline at end of file
+}
+
+#[derive(Deserialize)]
+struct TokenReq {
+  grant_type: String,
+  refresh_token: String
+}
+
+/**
+ * OAuth 2.0 style refresh for clients that keep the refresh token themselves (`"token_delivery": "body"`),
+ * takes a form or json body with `grant_type=refresh_token`
+ */
+#[post("/token")]
+async fn token(req: HttpRequest, keys: web::Data<KeyRingRef>, rts: web::Data<RefreshTokenStore>, lifetimes: web::Data<LifetimePolicyRef>, db: web::Data<DbRef>, logger: web::Data<LoggerRef>, body: web::Either<web::Form<TokenReq>, web::Json<TokenReq>>) -> Result<impl Responder, AppError> {
+  let body = match body {
+    web::Either::Left(form) => form.into_inner(),
+    web::Either::Right(json) => json.into_inner()
+  };
+  if body.grant_type != "refresh_token" {
+    return Err(AppError::user_error(400, "UNSUPPORTED_GRANT_TYPE", Some("grant_type must be refresh_token")));
+  }
+
+  let logger = logger.write().unwrap().clone();
+
+  let tokens = token_store::refresh_tokens(logger, &keys, &rts, &lifetimes, &db, body.refresh_token, ClientInfo::from_request(&req)).await?;
+
+  Ok(ok_res!(
+    200,
+    json!({
+      "access_token": tokens.access_token,
+      "token_type": "Bearer",
+      "expires_in": tokens.access_token_ttl,
+      "refresh_token": tokens.refresh_token,
+      "refresh_token_expires_in": tokens.refresh_token_ttl
+    })
+  ))
+}
+
+#[derive(Deserialize)]
+struct LogoutReq {
+  refresh_token: Option<String>
+}
+
+/**
+ * revokes the session of the refresh token in the refresh cookie (or the json body), with all its access tokens,
+ * and denies the access token in the Authorization header (if any), always clears the cookie
+ */
+#[post("/logout")]
+#[allow(clippy::too_many_arguments)]
+async fn logout(req: HttpRequest, keys: web::Data<KeyRingRef>, rts: web::Data<RefreshTokenStore>, lifetimes: web::Data<LifetimePolicyRef>, cookies: web::Data<CookiePolicyRef>, origins: web::Data<OriginPolicyRef>, logger: web::Data<LoggerRef>, body: Option<web::Json<LogoutReq>>) -> Result<impl Responder, AppError> {
+  let reftok = match body.and_then(|body| body.into_inner().refresh_token) {
+    Some(v) => Some(v),
+    None => {
+      // the cookie is sent cross site, so it alone must not end a session from another origin
+      let reftok = cookies.read(&req)?;
+      if reftok.is_some() {
+        origins.check_request(&req)?;
+      }
+      reftok
+    }
+  };
+
+  if let Some(reftok) = reftok {
+    let logger = logger.write().unwrap().clone();
+    token_store::end_session(logger, &keys, &rts, &lifetimes, reftok).await?;
+  }
+
+  let access_claims = bearer_token(&req).ok()
+  .and_then(|at| JWTClaim::decode_access_token(&keys, at).ok());
+  if let Some(claims) = access_claims {
+    claims.revoke(&rts).await?;
+  }
+
+  Ok(ok_res!(200, "logged out", cookies.expired_cookie()))
+}
+
+/**
+ * ends every session of the authenticated user and revokes every access token issued so far, see `token_store::end_all_sessions`
+ */
+#[post("/logoutAll", wrap = "RequireScope::new(\"write\")")]
+async fn logout_all(user: AuthenticatedUser, rts: web::Data<RefreshTokenStore>, lifetimes: web::Data<LifetimePolicyRef>, cookies: web::Data<CookiePolicyRef>) -> Result<impl Responder, AppError> {
+  let revoked = token_store::end_all_sessions(&rts, &lifetimes, &user.user_id.to_string()).await?;
+
+  Ok(ok_res!(
+    200,
+    json!({
+      "revoked_sessions": revoked
+    }),
+    cookies.expired_cookie()
+  ))
+}
+
+#[derive(Serialize)]
+struct SessionRes {
+  id: String,
+  /**
+   * the session of the access token making the request
+   */
+  current: bool,
+  #[serde(flatten)]
+  info: SessionInfo
+}
+
+/**
+ * the user's live sessions, most recently refreshed first
+ */
+#[get("/sessions", wrap = "RequireScope::new(\"read\")")]
+async fn list_sessions(user: AuthenticatedUser, rts: web::Data<RefreshTokenStore>) -> Result<impl Responder, AppError> {
+  let mut sessions: Vec<SessionRes> = rts.list_sessions(&user.user_id.to_string()).await?
+  .into_iter()
+  .map(|(id, info)| SessionRes {
+    current: user.sid.as_deref() == Some(id.as_str()),
+    id,
+    info
+  })
+  .collect();
+  sessions.sort_by_key(|s| std::cmp::Reverse(s.info.last_refreshed_at));
+
+  Ok(ok_res!(200, sessions))
+}
+
+/**
+ * revokes one of the user's sessions, its refresh token stops working and so do its access tokens.
+ * Ending the current session also clears the cookie
+ */
+#[delete("/sessions/{id}", wrap = "RequireScope::new(\"write\")")]
+async fn delete_session(user: AuthenticatedUser, rts: web::Data<RefreshTokenStore>, lifetimes: web::Data<LifetimePolicyRef>, cookies: web::Data<CookiePolicyRef>, path: web::Path<String>) -> Result<impl Responder, AppError> {
+  let not_found = |sid: &str| AppError::user_error(404, "SESSION_NOT_FOUND", Some(format!("no live session {}", sid)));
+  // session ids are uuids, anything else never reaches the store keys
+  let sid = match uuid::Uuid::parse_str(&path) {
+    Ok(v) => v.to_string(),
+    Err(_) => return Err(not_found(&path))
+  };
+  let user_id = user.user_id.to_string();
+  if rts.get_session(&user_id, &sid).await?.is_none() {
+    return Err(not_found(&sid));
+  }
+
+  rts.revoke_family(&user_id, &sid, lifetimes.max_refresh().max(lifetimes.max_access())).await?;
+
+  let mut res = HttpResponse::Ok();
+  if user.sid.as_deref() == Some(sid.as_str()) {
+    res.cookie(cookies.expired_cookie());
+  }
+  Ok(res.json(ok_res_inner!(200, "session revoked")))
+}
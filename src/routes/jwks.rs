@@ -0,0 +1,17 @@
+use actix_web::{Responder, HttpResponse, web, http::header::{CacheControl, CacheDirective}};
+
+use crate::{auth::keys::{KeyRing, KeyRingRef}, errors::AppError};
+
+/**
+ * public keys for verifying access tokens, served as a bare JWK set (not wrapped in GenericResponse)
+ * so standard JWT libraries can consume it
+ */
+#[get("/.well-known/jwks.json")]
+async fn jwks(keys: web::Data<KeyRingRef>) -> Result<impl Responder, AppError> {
+  let jwks = KeyRing::get_readable(&keys)?.jwks();
+  Ok(
+    HttpResponse::Ok()
+    .insert_header(CacheControl(vec![CacheDirective::Public, CacheDirective::MaxAge(300)]))
+    .json(jwks)
+  )
+}
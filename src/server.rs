@@ -1,58 +1,73 @@
 use std::sync::{RwLock, Arc};
 
-use actix_cors::Cors;
 use actix_web::{HttpServer, App, web};
 use slog::Logger;
 
-use crate::{config::FromEnv, errors::AppError, routes, db::DbRef, RefreshTokenStore, utils::logger::LoggerRef};
+use crate::{config::{FromConfig, AppConfig, ConfigRef}, errors::AppError, routes, middlewares::cors::cors, db::DbRef, db::token_store::RefreshTokenStore, utils::logger::LoggerRef, auth::{keys::KeyRingRef, lifetimes::{LifetimePolicy, LifetimePolicyRef}, cookies::{CookiePolicy, CookiePolicyRef}, origins::{OriginPolicy, OriginPolicyRef}, passwords::{PasswordHashing, PasswordHashingRef}, password_policy::{PasswordPolicy, PasswordPolicyRef}}};
 
 pub struct Server {
   logger: Logger,
-  addr: String
+  addr: String,
+  config: ConfigRef
 }
 
 impl Server {
-  pub async fn start(&self, db: DbRef, rts: RefreshTokenStore) -> Result<(), AppError> {
+  pub async fn start(&self, db: DbRef, rts: RefreshTokenStore, keys: KeyRingRef) -> Result<(), AppError> {
     info!(self.logger, "> Starting server...");
     info!(self.logger, "> http://{}", self.addr);
     let logger = self.logger.clone();
     let logger_ref: LoggerRef = Arc::new(RwLock::new(logger.clone()));
+    let config = self.config.clone();
+    let lifetimes: LifetimePolicyRef = Arc::new(LifetimePolicy::from_config(logger.clone(), &config));
+    let cookies: CookiePolicyRef = Arc::new(CookiePolicy::from_config(logger.clone(), &config));
+    let origins: OriginPolicyRef = Arc::new(OriginPolicy::from_config(logger.clone(), &config));
+    let passwords: PasswordHashingRef = Arc::new(PasswordHashing::from_config(logger.clone(), &config)?);
+    let password_policy: PasswordPolicyRef = Arc::new(PasswordPolicy::from_config(logger.clone(), &config)?);
+    let public_origins: OriginPolicyRef = Arc::new(OriginPolicy::new(config.cors_public.allowed_origins.clone()));
     HttpServer::new(move || {
-      let cors = Cors::default()
-      .allow_any_header()
-      .allow_any_method()
-      .allow_any_origin()
-      .supports_credentials();
-
       App::new()
-      .wrap(cors)
       .app_data(web::Data::new(rts.clone()))
       .app_data(web::Data::new(db.clone()))
       .app_data(web::Data::new(logger_ref.clone()))
-      .service(routes::home::home)
-      .service(routes::auth::auth_scope())
+      .app_data(web::Data::new(config.clone()))
+      .app_data(web::Data::new(keys.clone()))
+      .app_data(web::Data::new(lifetimes.clone()))
+      .app_data(web::Data::new(cookies.clone()))
+      .app_data(web::Data::new(origins.clone()))
+      .app_data(web::Data::new(passwords.clone()))
+      .app_data(web::Data::new(password_policy.clone()))
+      .service(routes::auth::auth_scope().wrap(cors(&config.cors_auth, origins.clone())))
+      .service(routes::admin::admin_scope())
+      // matches every path, must come after the other scopes
+      .service(
+        web::scope("")
+        .wrap(cors(&config.cors_public, public_origins.clone()))
+        .service(routes::home::home)
+        .service(routes::jwks::jwks)
+      )
     })
     .bind(self.addr.clone())?
     .run()
     .await
-    .map_err(|err| AppError::from(err))
+    .map_err(AppError::from)
   }
 
   /**
-  Verbosely starts the server with configs given from env
+  Verbosely starts the server with the resolved app config
    */
-  pub async fn start_from_env(db: DbRef, logger: Logger, rts: RefreshTokenStore) -> Result<(), AppError> {
-    let srv = Self::from_env(logger);
-    srv.start(db, rts).await
+  pub async fn start_from_config(db: DbRef, logger: Logger, config: &AppConfig, rts: RefreshTokenStore, keys: KeyRingRef) -> Result<(), AppError> {
+    let srv = Self::from_config(logger, config);
+    srv.start(db, rts, keys).await
   }
 }
 
-impl FromEnv for Server {
+impl FromConfig for Server {
   type Item = Self;
-  fn from_env(logger: Logger) -> Self {
+  fn from_config(logger: Logger, config: &AppConfig) -> Self {
     Self {
       logger,
-      addr: dotenv!("HOST_ADDR").into()
+      addr: config.host_addr.clone(),
+      config: Arc::new(config.clone())
     }
   }
 }
\ No newline at end of file